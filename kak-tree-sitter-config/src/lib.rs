@@ -101,15 +101,46 @@ impl FeaturesConfig {
 
 /// Highlight configuration.
 ///
-/// This is a set of capture groups that can be found in various queries.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// Capture groups are discovered from the queries of each language; this configuration filters them. Filtering a
+/// group filters its sub-groups as well; e.g. denying `markup` denies `markup.heading`, too.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct HighlightConfig {
-  pub groups: HashSet<String>,
+  /// Groups to highlight; all groups are highlighted if not set.
+  #[serde(default)]
+  pub allow: Option<HashSet<String>>,
+
+  /// Groups to never highlight.
+  #[serde(default)]
+  pub deny: HashSet<String>,
+
+  /// Combine the faces of nested captures instead of using the innermost one only.
+  #[serde(default)]
+  pub layered: bool,
 }
 
 impl HighlightConfig {
   fn merge_user_config(&mut self, user_config: UserHighlightConfig) {
-    self.groups.extend(user_config.groups);
+    if let Some(allow) = user_config.allow {
+      self.allow.get_or_insert_with(HashSet::new).extend(allow);
+    }
+
+    if let Some(deny) = user_config.deny {
+      self.deny.extend(deny);
+    }
+
+    self.layered = user_config.layered.unwrap_or(self.layered);
+  }
+
+  /// Check whether a group should be highlighted.
+  pub fn is_group_highlighted(&self, group: &str) -> bool {
+    // the group itself and all its parents; e.g. a, a.b and a.b.c for a.b.c
+    let lineage = group
+      .match_indices('.')
+      .map(|(i, _)| &group[..i])
+      .chain(Some(group));
+    let is_filtered = |filter: &HashSet<String>| lineage.clone().any(|g| filter.contains(g));
+
+    self.allow.as_ref().map_or(true, is_filtered) && !is_filtered(&self.deny)
   }
 }
 
@@ -218,6 +249,14 @@ pub struct LanguageConfig {
 
   #[serde(default)]
   pub remove_default_highlighter: RemoveDefaultHighlighter,
+
+  /// Extensions of the files written in this language; used to index files that are not opened in Kakoune.
+  #[serde(default)]
+  pub extensions: Vec<String>,
+
+  /// Templates nodes are wrapped with, by name; `@node` is substituted with the wrapped node.
+  #[serde(default)]
+  pub wrap: HashMap<String, String>,
 }
 
 impl LanguageConfig {
@@ -234,6 +273,14 @@ impl LanguageConfig {
       .unwrap_or(self.remove_default_highlighter.0)
       .into();
 
+    if let Some(extensions) = user_config.extensions {
+      self.extensions = extensions;
+    }
+
+    if let Some(wrap) = user_config.wrap {
+      self.wrap.extend(wrap);
+    }
+
     Ok(())
   }
 }
@@ -256,6 +303,8 @@ impl TryFrom<UserLanguageConfig> for LanguageConfig {
         .remove_default_highlighter
         .unwrap_or(true)
         .into(),
+      extensions: user_config.extensions.unwrap_or_default(),
+      wrap: user_config.wrap.unwrap_or_default(),
     })
   }
 }
@@ -472,7 +521,9 @@ pub struct UserFeaturesConfig {
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserHighlightConfig {
-  pub groups: HashSet<String>,
+  pub allow: Option<HashSet<String>>,
+  pub deny: Option<HashSet<String>>,
+  pub layered: Option<bool>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -480,6 +531,8 @@ pub struct UserLanguageConfig {
   pub grammar: Option<UserLanguageGrammarConfig>,
   pub queries: Option<UserLanguageQueriesConfig>,
   pub remove_default_highlighter: Option<bool>,
+  pub extensions: Option<Vec<String>>,
+  pub wrap: Option<HashMap<String, String>>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -502,7 +555,7 @@ pub struct UserLanguageQueriesConfig {
 
 #[cfg(test)]
 mod tests {
-  use std::path::PathBuf;
+  use std::{collections::HashMap, path::PathBuf};
 
   use crate::{
     source::{Source, UserSource},
@@ -520,9 +573,11 @@ mod tests {
         text_objects: true,
       },
       highlight: HighlightConfig {
-        groups: ["foo".to_owned(), "bar".to_owned(), "zoo".to_owned()]
+        allow: None,
+        deny: ["foo".to_owned(), "bar".to_owned(), "zoo".to_owned()]
           .into_iter()
           .collect(),
+        layered: false,
       },
       languages: LanguagesConfig {
         language: [(
@@ -543,6 +598,8 @@ mod tests {
               path: PathBuf::from("runtime/queries/rust"),
             },
             remove_default_highlighter: true.into(),
+            extensions: vec!["rs".to_owned()],
+            wrap: HashMap::default(),
           },
         )]
         .into_iter()
@@ -599,6 +656,24 @@ mod tests {
     }
   }
 
+  #[test]
+  fn highlight_group_filters() {
+    let mut config = HighlightConfig::default();
+    assert!(config.is_group_highlighted("keyword.control"));
+
+    config.deny.insert("markup".to_owned());
+    assert!(!config.is_group_highlighted("markup"));
+    assert!(!config.is_group_highlighted("markup.heading.1"));
+    assert!(config.is_group_highlighted("markupish"));
+
+    config.allow = Some(["keyword".to_owned(), "markup".to_owned()].into());
+    assert!(config.is_group_highlighted("keyword"));
+    assert!(config.is_group_highlighted("keyword.control"));
+    assert!(!config.is_group_highlighted("keywords"));
+    assert!(!config.is_group_highlighted("markup.heading"));
+    assert!(!config.is_group_highlighted("function"));
+  }
+
   #[test]
   fn user_config() -> Result<(), ConfigError> {
     let toml = r#"[language.rust.grammar.source.git]
@@ -34,6 +34,9 @@ pub enum OhNo {
   #[error("cannot create file {file}: {err}")]
   CannotCreateFile { file: PathBuf, err: io::Error },
 
+  #[error("cannot read file {file}: {err}")]
+  CannotReadFile { file: PathBuf, err: io::Error },
+
   #[error("cannot write to file {file}: {err}")]
   CannotWriteFile { file: PathBuf, err: io::Error },
 
@@ -55,6 +58,12 @@ pub enum OhNo {
   #[error("cannot read FIFO: {err}")]
   CannotReadFifo { err: io::Error },
 
+  #[error("invalid header in buffer update")]
+  InvalidBufferHeader,
+
+  #[error("buffer modifications do not apply to the buffer known by the server")]
+  InvalidBufferModifications,
+
   #[error("poll error: {err}")]
   PollError { err: io::Error },
 
@@ -82,15 +91,24 @@ pub enum OhNo {
   #[error("cannot parse buffer")]
   CannotParseBuffer,
 
-  #[error("highlight error: {err}")]
-  HighlightError { err: String },
-
   #[error("unknown language: {lang}")]
   UnknownLang { lang: String },
 
   #[error("unknown buffer: {id:?}")]
   UnknownBuffer { id: BufferId },
 
+  #[error(
+    "buffer {id:?} is at timestamp {current}, but the request was made at timestamp {timestamp}"
+  )]
+  OutdatedBuffer {
+    id: BufferId,
+    timestamp: u64,
+    current: u64,
+  },
+
+  #[error("unknown session: {session}")]
+  UnknownSession { session: String },
+
   #[error("unknown buffer token: {tkn:?}")]
   UnknownToken { tkn: Token },
 
@@ -111,4 +129,28 @@ pub enum OhNo {
 
   #[error("no such {pattern} text-object query")]
   UnknownTextObjectQuery { pattern: String },
+
+  #[error("no such capture @{capture} in query")]
+  UnknownCapture { capture: String },
+
+  #[error("no such wrap template {template}")]
+  UnknownWrapTemplate { template: String },
+
+  #[error("split/join not supported")]
+  UnsupportedSplitJoin,
+
+  #[error("locals not supported")]
+  UnsupportedLocals,
+
+  #[error("indents not supported")]
+  UnsupportedIndents,
+
+  #[error("folds not supported")]
+  UnsupportedFolds,
+
+  #[error("tags not supported")]
+  UnsupportedTags,
+
+  #[error("context not supported")]
+  UnsupportedContext,
 }
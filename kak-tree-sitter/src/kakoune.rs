@@ -1,5 +1,9 @@
 pub mod buffer;
+pub mod faces;
+pub mod history;
 pub mod rc;
+pub mod remote;
 pub mod selection;
 pub mod session;
 pub mod text_objects;
+pub mod window;
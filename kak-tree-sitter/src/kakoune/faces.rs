@@ -0,0 +1,82 @@
+//! Faces used to highlight buffers.
+
+use std::collections::HashSet;
+
+/// Face used to highlight a capture group; e.g. `ts_keyword_control` for `keyword.control`.
+pub fn group_face(group: &str) -> String {
+  format!("ts_{}", group.replace('.', "_"))
+}
+
+/// Fallback faces of capture groups.
+///
+/// Each group falls back to its parent group — e.g. `keyword.control.repeat` falls back to `keyword.control`, which
+/// falls back to `keyword` — and top-level groups fall back to `default`, so that colorschemes only have to define
+/// top-level faces. Faces in `defined` are left untouched.
+///
+/// Return a list of (face, fallback face), parents first.
+pub fn fallbacks<'a>(
+  groups: impl IntoIterator<Item = &'a str>,
+  defined: &HashSet<&str>,
+) -> Vec<(String, String)> {
+  let mut defined: HashSet<String> = defined.iter().map(|&face| face.to_owned()).collect();
+  let mut fallbacks = Vec::new();
+
+  for group in groups {
+    let mut chain = Vec::new();
+    let mut group = group;
+
+    loop {
+      let face = group_face(group);
+      if defined.contains(&face) {
+        break;
+      }
+
+      let parent = group.rsplit_once('.').map(|(parent, _)| parent);
+      let parent_face = parent.map_or_else(|| "default".to_owned(), group_face);
+      defined.insert(face.clone());
+      chain.push((face, parent_face));
+
+      match parent {
+        Some(parent) => group = parent,
+        None => break,
+      }
+    }
+
+    fallbacks.extend(chain.into_iter().rev());
+  }
+
+  fallbacks
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use super::fallbacks;
+
+  #[test]
+  fn face_fallbacks() {
+    let defined = HashSet::from(["ts_keyword", "ts_comment"]);
+    let fallbacks = fallbacks(
+      [
+        "keyword.control.repeat",
+        "markup.heading.1",
+        "markup.heading",
+        "comment",
+      ],
+      &defined,
+    );
+
+    assert_eq!(
+      fallbacks,
+      [
+        ("ts_keyword_control", "ts_keyword"),
+        ("ts_keyword_control_repeat", "ts_keyword_control"),
+        ("ts_markup", "default"),
+        ("ts_markup_heading", "ts_markup"),
+        ("ts_markup_heading_1", "ts_markup_heading"),
+      ]
+      .map(|(face, parent)| (face.to_owned(), parent.to_owned()))
+    );
+  }
+}
@@ -0,0 +1,143 @@
+//! Buffer modifications, as exposed by Kakoune with `%val{uncommitted_modifications}`.
+
+use super::selection::Pos;
+
+/// Kind of a [`Modification`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModificationKind {
+  Insert,
+  Delete,
+}
+
+/// A single modification of a buffer; text inserted or deleted at a given position.
+///
+/// Positions are _1-based_, and columns are expressed in bytes. Modifications apply one after the other, so the
+/// position of a modification is relative to the buffer with the previous modifications applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Modification {
+  pub kind: ModificationKind,
+  pub pos: Pos,
+  pub text: String,
+}
+
+impl Modification {
+  /// Read a [`Modification`] from a Kakoune-formatted string; i.e. `+<line>.<col>|<text>` for insertions, and
+  /// `-<line>.<col>|<text>` for deletions.
+  ///
+  /// Return [`None`] if parsing failed.
+  pub fn parse_kak_str(s: &str) -> Option<Self> {
+    let kind = match s.chars().next()? {
+      '+' => ModificationKind::Insert,
+      '-' => ModificationKind::Delete,
+      _ => return None,
+    };
+    let (pos, text) = s[1..].split_once('|')?;
+    let pos = Pos::parse_kak_str(pos).filter(|pos| pos.line > 0 && pos.col > 0)?;
+
+    Some(Self {
+      kind,
+      pos,
+      text: text.to_owned(),
+    })
+  }
+}
+
+/// Modifications of a buffer since its last undo group was committed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UncommittedModifications {
+  /// Content of `%val{history_id}`; i.e. the last committed undo group.
+  pub history_id: u64,
+
+  /// Content of `%val{buf_line_count}`, once the modifications are applied.
+  pub line_count: usize,
+
+  /// Content of `%val{uncommitted_modifications}`.
+  pub modifications: Vec<Modification>,
+}
+
+/// Parse words quoted with `echo -quoting kakoune`, up to an unquoted `;`.
+///
+/// Return the words, and what follows the `;`.
+pub fn parse_quoted_words(s: &str) -> Option<(Vec<String>, &str)> {
+  let mut words = Vec::new();
+  let mut rest = s;
+
+  loop {
+    rest = rest.trim_start_matches(' ');
+
+    if let Some(after) = rest.strip_prefix(';') {
+      return Some((words, after));
+    }
+
+    // quotes inside a word are doubled
+    let mut word = String::new();
+    let mut quoted = rest.strip_prefix('\'')?;
+    loop {
+      let end = quoted.find('\'')?;
+      word.push_str(&quoted[..end]);
+      quoted = &quoted[end + 1..];
+
+      match quoted.strip_prefix('\'') {
+        Some(after) => {
+          word.push('\'');
+          quoted = after;
+        }
+
+        None => break,
+      }
+    }
+
+    words.push(word);
+    rest = quoted;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{parse_quoted_words, Modification, ModificationKind};
+  use crate::kakoune::selection::Pos;
+
+  #[test]
+  fn modification_parsing() {
+    assert_eq!(
+      Modification::parse_kak_str("+12.3|foo|bar"),
+      Some(Modification {
+        kind: ModificationKind::Insert,
+        pos: Pos { line: 12, col: 3 },
+        text: "foo|bar".to_owned(),
+      })
+    );
+    assert_eq!(
+      Modification::parse_kak_str("-1.1|\n"),
+      Some(Modification {
+        kind: ModificationKind::Delete,
+        pos: Pos { line: 1, col: 1 },
+        text: "\n".to_owned(),
+      })
+    );
+    assert_eq!(Modification::parse_kak_str("*1.1|a"), None);
+    assert_eq!(Modification::parse_kak_str("+1.1"), None);
+    assert_eq!(Modification::parse_kak_str("+0.1|a"), None);
+  }
+
+  #[test]
+  fn quoted_words_parsing() {
+    assert_eq!(
+      parse_quoted_words("'12' 'edits' '+1.1|it''s; done';rest"),
+      Some((
+        vec![
+          "12".to_owned(),
+          "edits".to_owned(),
+          "+1.1|it's; done".to_owned()
+        ],
+        "rest"
+      ))
+    );
+    assert_eq!(
+      parse_quoted_words(";content"),
+      Some((Vec::new(), "content"))
+    );
+    assert_eq!(parse_quoted_words("'12' 'unterminated"), None);
+    assert_eq!(parse_quoted_words("'12'"), None);
+  }
+}
@@ -1,11 +1,56 @@
 //! rc file used by Kakoune to inject kak-tree-sitter commands.
 
+use std::collections::HashSet;
+
 /// Main RC file.
 pub fn static_kak() -> &'static str {
   include_str!("../../rc/static.kak")
 }
 
+/// Faces defined in the main RC file.
+pub fn static_faces() -> HashSet<&'static str> {
+  static_kak()
+    .lines()
+    .filter_map(|line| {
+      line
+        .strip_prefix("set-face global ")?
+        .split_whitespace()
+        .next()
+    })
+    .collect()
+}
+
 /// Text-objects related file.
 pub fn text_objects_kak() -> &'static str {
   include_str!("../../rc/text-objects.kak")
 }
+
+#[cfg(test)]
+mod tests {
+  use super::static_kak;
+
+  #[test]
+  fn static_face_fallbacks() {
+    // faces of nested groups fall back to the face of their direct parent group
+    for line in static_kak().lines() {
+      let Some(face) = line.strip_prefix("set-face global ") else {
+        continue;
+      };
+      let mut words = face.split_whitespace();
+      let (Some(face), Some(fallback)) = (words.next(), words.next()) else {
+        continue;
+      };
+
+      // faces not standing for capture groups
+      if ["ts_fold", "ts_local_definition"].contains(&face) || !fallback.starts_with("ts_") {
+        continue;
+      }
+
+      assert_eq!(
+        face.rsplit_once('_').map(|(parent, _)| parent),
+        Some(fallback),
+        "{face}"
+      );
+    }
+  }
+}
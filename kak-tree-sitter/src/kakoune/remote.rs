@@ -0,0 +1,137 @@
+//! Kakoune remote protocol.
+//!
+//! Kakoune sessions listen on a UNIX socket, on which remote clients (`kak -c`) and `kak -p` connect. This module
+//! implements the part of the protocol used by `kak -p`, which allows to send commands to a session.
+
+use std::{env, ffi::CStr, io::Write, os::unix::net::UnixStream, path::PathBuf};
+
+use crate::error::OhNo;
+
+/// Type of a command message.
+const MSG_COMMAND: u8 = 2;
+
+/// Size of the header of a message; i.e. its type and its size.
+const MSG_HEADER_SIZE: usize = 1 + 4;
+
+/// Remote connection to a Kakoune session.
+///
+/// Kakoune closes the connection once a command has been executed, so a new connection is made for each command; the
+/// path to the socket of the session is computed once and reused, though.
+#[derive(Debug)]
+pub struct Remote {
+  socket_path: PathBuf,
+}
+
+impl Remote {
+  /// Create a [`Remote`] for the given session.
+  pub fn new(session: &str) -> Self {
+    Self::with_socket_path(session_dir().join(session))
+  }
+
+  /// Create a [`Remote`] connecting to the given socket.
+  pub fn with_socket_path(socket_path: impl Into<PathBuf>) -> Self {
+    Self {
+      socket_path: socket_path.into(),
+    }
+  }
+
+  /// Send a command to the session.
+  pub fn send_command(&self, cmd: &str) -> Result<(), OhNo> {
+    let mut stream =
+      UnixStream::connect(&self.socket_path).map_err(|err| OhNo::CannotSendRequest {
+        err: format!(
+          "cannot connect to Kakoune session at {path}: {err}",
+          path = self.socket_path.display()
+        ),
+      })?;
+
+    stream
+      .write_all(&encode_command(cmd))
+      .and_then(|_| stream.flush())
+      .map_err(|err| OhNo::CannotSendRequest {
+        err: err.to_string(),
+      })
+  }
+}
+
+/// Directory where Kakoune puts the sockets of its sessions.
+///
+/// That is `$XDG_RUNTIME_DIR/kakoune` if set, or `$TMPDIR/kakoune-$USER` otherwise.
+fn session_dir() -> PathBuf {
+  if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+    return PathBuf::from(runtime_dir).join("kakoune");
+  }
+
+  let tmp_dir = env::var("TMPDIR")
+    .ok()
+    .map(|dir| dir.trim_end_matches('/').to_owned())
+    .filter(|dir| !dir.is_empty())
+    .unwrap_or_else(|| "/tmp".to_owned());
+  PathBuf::from(tmp_dir).join(format!("kakoune-{}", user_name()))
+}
+
+/// Name of the user, the same way Kakoune gets it.
+fn user_name() -> String {
+  // SAFETY: getpwuid returns either NULL or a pointer to a static entry, which we copy right away
+  let name = unsafe {
+    let pw = libc::getpwuid(libc::geteuid());
+    (!pw.is_null()).then(|| CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned())
+  };
+
+  name.or_else(|| env::var("USER").ok()).unwrap_or_default()
+}
+
+/// Encode a command message.
+///
+/// A message starts with its type and its total size (header included), followed by its content. Strings are encoded
+/// with their length, followed by their bytes. Integers are encoded in native endianness.
+fn encode_command(cmd: &str) -> Vec<u8> {
+  let size = MSG_HEADER_SIZE + 4 + cmd.len();
+  let mut msg = Vec::with_capacity(size);
+
+  msg.push(MSG_COMMAND);
+  msg.extend_from_slice(&(size as u32).to_ne_bytes());
+  msg.extend_from_slice(&(cmd.len() as u32).to_ne_bytes());
+  msg.extend_from_slice(cmd.as_bytes());
+
+  msg
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Read, os::unix::net::UnixListener, thread};
+
+  use super::{encode_command, Remote, MSG_COMMAND};
+
+  #[test]
+  fn encode_command_message() {
+    let msg = encode_command("echo hello");
+
+    assert_eq!(msg[0], MSG_COMMAND);
+    assert_eq!(msg[1..5], 19u32.to_ne_bytes());
+    assert_eq!(msg[5..9], 10u32.to_ne_bytes());
+    assert_eq!(&msg[9..], b"echo hello");
+  }
+
+  #[test]
+  fn send_command() {
+    let socket_path = std::env::temp_dir().join(format!("kts-remote-{}", uuid::Uuid::new_v4()));
+    let listener = UnixListener::bind(&socket_path).unwrap();
+
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut msg = Vec::new();
+      stream.read_to_end(&mut msg).unwrap();
+      msg
+    });
+
+    Remote::with_socket_path(&socket_path)
+      .send_command("echo hello")
+      .unwrap();
+
+    let msg = server.join().unwrap();
+    std::fs::remove_file(&socket_path).unwrap();
+
+    assert_eq!(msg, encode_command("echo hello"));
+  }
+}
@@ -85,6 +85,31 @@ impl Sel {
     )
   }
 
+  /// Create a [`Sel`] selecting a tree-sitter range, from its start to its end.
+  ///
+  /// A range ending at the start of a line ends on the newline of the previous line, whose length is read from the
+  /// source the range comes from.
+  pub fn from_ts_range(range: &tree_sitter::Range, source: &str) -> Self {
+    let cursor = if range.end_point.column == 0 && range.end_point.row > 0 {
+      let newline = range.end_byte - 1;
+      let line_start = source[..newline].rfind('\n').map_or(0, |i| i + 1);
+
+      Pos {
+        line: range.end_point.row,
+        col: newline - line_start + 1,
+      }
+    } else {
+      let mut cursor: Pos = range.end_point.into();
+      cursor.col -= 1; // kakoune selections are inclusive
+      cursor
+    };
+
+    Self {
+      anchor: range.start_point.into(),
+      cursor,
+    }
+  }
+
   /// Replace a selection with two other points.
   ///
   /// This function replaces the selection with two other points by keeping the order anchor / cursor; if the anchor is
@@ -216,6 +241,43 @@ mod tests {
     );
   }
 
+  #[test]
+  fn sel_from_ts_range() {
+    let range = tree_sitter::Range {
+      start_byte: 13,
+      end_byte: 16,
+      start_point: tree_sitter::Point::new(1, 2),
+      end_point: tree_sitter::Point::new(1, 5),
+    };
+
+    assert_eq!(
+      Sel::from_ts_range(&range, "fn main() {\n  let x = 1;\n}"),
+      Sel {
+        anchor: Pos { line: 2, col: 3 },
+        cursor: Pos { line: 2, col: 5 },
+      }
+    );
+  }
+
+  #[test]
+  fn sel_from_ts_range_line_start() {
+    // the range ends right after the newline of the second line
+    let range = tree_sitter::Range {
+      start_byte: 14,
+      end_byte: 25,
+      start_point: tree_sitter::Point::new(1, 2),
+      end_point: tree_sitter::Point::new(2, 0),
+    };
+
+    assert_eq!(
+      Sel::from_ts_range(&range, "fn main() {\n  let x = 1;\n}"),
+      Sel {
+        anchor: Pos { line: 2, col: 3 },
+        cursor: Pos { line: 2, col: 13 },
+      }
+    );
+  }
+
   #[test]
   fn replace_sel() {
     let anchor_cursor = Sel {
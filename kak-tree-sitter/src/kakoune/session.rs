@@ -1,10 +1,8 @@
-use std::{
-  collections::HashMap,
-  io::Write,
-  process::{Command, Stdio},
-};
+use std::{collections::HashMap, ops::Range};
 
-use crate::{error::OhNo, protocol::response::Response};
+use crate::error::OhNo;
+
+use super::{buffer::BufferId, window::WindowRange};
 
 /// Session tracker.
 ///
@@ -35,58 +33,68 @@ impl SessionTracker {
   pub fn sessions(&self) -> impl Iterator<Item = &str> {
     self.sessions.keys().map(String::as_str)
   }
+
+  pub fn get_mut(&mut self, session_name: &str) -> Option<&mut Session> {
+    self.sessions.get_mut(session_name)
+  }
+
+  /// Lines displayed by all the clients of a session showing a given buffer.
+  pub fn viewports(&self, id: &BufferId) -> Vec<Range<usize>> {
+    self
+      .sessions
+      .get(id.session())
+      .map(|session| session.viewports(id.buffer()).collect())
+      .unwrap_or_default()
+  }
 }
 
 /// An (active) session.
 #[derive(Debug)]
 pub struct Session {
   name: String,
+  clients: HashMap<String, ClientWindow>,
+}
+
+/// Window of a client, along with the buffer it displays.
+#[derive(Debug)]
+struct ClientWindow {
+  buffer: String,
+  range: WindowRange,
 }
 
 impl Session {
   /// Create a new [`Session`] for the given name.
   pub fn new(name: impl Into<String>) -> Result<Self, OhNo> {
-    Ok(Self { name: name.into() })
+    Ok(Self {
+      name: name.into(),
+      clients: HashMap::default(),
+    })
+  }
+
+  /// Track the window of a client.
+  pub fn set_client_window(
+    &mut self,
+    client: impl Into<String>,
+    buffer: impl Into<String>,
+    range: WindowRange,
+  ) {
+    let buffer = buffer.into();
+    self
+      .clients
+      .insert(client.into(), ClientWindow { buffer, range });
+  }
+
+  /// Stop tracking the window of a closed client.
+  pub fn remove_client(&mut self, client: &str) {
+    self.clients.remove(client);
   }
 
-  /// Send a response back to Kakoune.
-  pub fn send_response(resp: Response) -> Result<(), OhNo> {
-    let Some(data) = resp.to_kak() else {
-      // FIXME: this is a weird situation where the [`Response`] doesn’t really
-      // have any Kakoune counterpart; I plan on removing that ~soon
-      return Ok(());
-    };
-
-    // spawn the kak -p process
-    // TODO: we want to switch that from directly connecting to the UNIX socket
-    let mut child = Command::new("kak")
-      .args(["-p", resp.session()])
-      .stdin(Stdio::piped())
-      .spawn()
-      .map_err(|err| OhNo::CannotSendRequest {
-        err: err.to_string(),
-      })?;
-    let child_stdin = child
-      .stdin
-      .as_mut()
-      .ok_or_else(|| OhNo::CannotSendRequest {
-        err: "cannot pipe data to kak -p".to_owned(),
-      })?;
-
-    child_stdin
-      .write_all(data.as_bytes())
-      .map_err(|err| OhNo::CannotSendRequest {
-        err: err.to_string(),
-      })?;
-
-    child_stdin.flush().map_err(|err| OhNo::CannotSendRequest {
-      err: err.to_string(),
-    })?;
-
-    child.wait().map_err(|err| OhNo::CannotSendRequest {
-      err: format!("error while waiting on kak -p: {err}"),
-    })?;
-
-    Ok(())
+  /// Lines displayed by the clients showing a given buffer.
+  pub fn viewports<'a>(&'a self, buffer: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+    self
+      .clients
+      .values()
+      .filter(move |window| window.buffer == buffer)
+      .map(|window| window.range.lines())
   }
 }
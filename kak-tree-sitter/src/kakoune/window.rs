@@ -0,0 +1,58 @@
+//! Client windows, as seen by Kakoune.
+
+use std::ops::Range;
+
+/// Area of a buffer displayed in a client window.
+///
+/// See `%val{window_range}`. Coordinates are _0-based_.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WindowRange {
+  pub line: usize,
+  pub column: usize,
+  pub height: usize,
+  pub width: usize,
+}
+
+impl WindowRange {
+  /// Read a [`WindowRange`] from Kakoune-formatted string; i.e. <line> <column> <height> <width>.
+  ///
+  /// Return [`None`] if parsing failed.
+  pub fn parse_kak_str(s: &str) -> Option<Self> {
+    let mut parts = s.split_whitespace().flat_map(|s| s.parse().ok());
+    let line = parts.next()?;
+    let column = parts.next()?;
+    let height = parts.next()?;
+    let width = parts.next()?;
+
+    Some(Self {
+      line,
+      column,
+      height,
+      width,
+    })
+  }
+
+  /// Lines displayed in the window.
+  pub fn lines(&self) -> Range<usize> {
+    self.line..self.line + self.height
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::WindowRange;
+
+  #[test]
+  fn parse_window_range() {
+    assert_eq!(
+      WindowRange::parse_kak_str("120 4 48 160"),
+      Some(WindowRange {
+        line: 120,
+        column: 4,
+        height: 48,
+        width: 160
+      })
+    );
+    assert_eq!(WindowRange::parse_kak_str("120 4 48"), None);
+  }
+}
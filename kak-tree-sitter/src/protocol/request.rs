@@ -2,7 +2,11 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::{error::OhNo, kakoune::text_objects::OperationMode, tree_sitter::nav};
+use crate::{
+  error::OhNo,
+  kakoune::text_objects::OperationMode,
+  tree_sitter::{diagnostics, folds, nav, structural, swap, syntax_tree},
+};
 
 use super::response::{self, Response};
 
@@ -39,6 +43,10 @@ impl Request {
     &self.session
   }
 
+  pub fn client(&self) -> Option<&str> {
+    self.client.as_deref()
+  }
+
   pub fn buffer(&self) -> Option<&str> {
     self.buffer.as_deref()
   }
@@ -77,11 +85,31 @@ pub enum Payload {
   /// Buffer metadata.
   ///
   /// This should be sent every time the buffer changes (lang, mostly).
-  BufferMetadata { lang: String },
+  BufferMetadata {
+    lang: String,
+
+    /// How the buffer should be updated when new content is streamed.
+    #[serde(default)]
+    update: BufferUpdate,
+  },
 
   /// Buffer close.
   BufferClose,
 
+  /// Window of a client.
+  ///
+  /// This should be sent every time the area of the buffer displayed by a client changes, so that highlighting that
+  /// area can be prioritized.
+  Window {
+    buffer: String,
+
+    /// Content of `%val{window_range}`.
+    range: String,
+  },
+
+  /// Inform the server that a client has been closed, so that the area it displayed is not prioritized anymore.
+  ClientClose { client: String },
+
   /// Request to apply text-objects on selections.
   TextObjects {
     buffer: String,
@@ -90,6 +118,155 @@ pub enum Payload {
     mode: OperationMode,
   },
 
+  /// Request to highlight the definition and references of the symbol under the cursor.
+  References {
+    buffer: String,
+
+    /// Position of the cursor, as `<line>.<column>`.
+    cursor: String,
+
+    /// Timestamp of the buffer the request was made on; the buffer is read from its FIFO first if needed.
+    timestamp: u64,
+  },
+
+  /// Request to select the definition and references of the symbols under the cursors of selections.
+  SelectReferences {
+    buffer: String,
+    selections: String,
+
+    /// Timestamp of the buffer the request was made on; the buffer is read from its FIFO first if needed.
+    timestamp: u64,
+  },
+
+  /// Request to fold or unfold the nodes on the lines of selections.
+  Fold {
+    buffer: String,
+    selections: String,
+
+    /// Content of `%opt{tree_sitter_folds}`; i.e. the currently folded ranges.
+    folded: String,
+    action: folds::Action,
+
+    /// Timestamp of the buffer the request was made on; the buffer is read from its FIFO first if needed.
+    timestamp: u64,
+  },
+
+  /// Request the breadcrumb of the context nodes enclosing the cursor.
+  Breadcrumb {
+    buffer: String,
+
+    /// Position of the cursor, as `<line>.<column>`.
+    cursor: String,
+
+    /// Timestamp of the buffer the request was made on; the buffer is read from its FIFO first if needed.
+    timestamp: u64,
+  },
+
+  /// Request to select the nodes captured by an arbitrary query inside selections.
+  SelectQuery {
+    buffer: String,
+    selections: String,
+    query: String,
+
+    /// Name of the capture to select, without the leading `@`.
+    capture: String,
+
+    /// Timestamp of the buffer the request was made on; the buffer is read from its FIFO first if needed.
+    timestamp: u64,
+  },
+
+  /// Request to replace every match of a query with a template.
+  Replace {
+    buffer: String,
+    query: String,
+
+    /// Replacement text, in which `@<capture>` is substituted with the text of the capture.
+    template: String,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
+  /// Request to run an arbitrary query against a buffer and highlight its captures.
+  QueryPlayground {
+    buffer: String,
+
+    /// Path of a file containing the query.
+    query_path: String,
+  },
+
+  /// Request the S-expression of the tree of a buffer, or of the node covering the main selection.
+  ShowTree {
+    buffer: String,
+
+    /// Main selection; its cursor is marked in the tree.
+    selection: String,
+    scope: syntax_tree::Scope,
+  },
+
+  /// Request to list the symbols defined in a buffer.
+  Symbols { buffer: String },
+
+  /// Request to index the symbols of the files of a directory, and to keep them up to date.
+  WorkspaceIndex { root: String },
+
+  /// Request to search symbols in a workspace, indexing it if needed.
+  WorkspaceSymbols { root: String, query: String },
+
+  /// Request to indent the lines of selections.
+  Indent {
+    buffer: String,
+    selections: String,
+
+    /// Content of `%opt{indentwidth}`; `0` means indenting with tabs.
+    indent_width: usize,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
+  /// Request to select the next or previous diagnostic of selections.
+  DiagnosticNav {
+    buffer: String,
+    selections: String,
+    dir: diagnostics::Dir,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
+  /// Request to swap the nodes under selections with their siblings.
+  Swap {
+    buffer: String,
+    selections: String,
+    dir: swap::Dir,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
+  /// Request to split or join the nodes around the cursors of selections.
+  SplitJoin {
+    buffer: String,
+    selections: String,
+
+    /// Content of `%opt{indentwidth}`; `0` means indenting with tabs.
+    indent_width: usize,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
+  /// Request to apply a structural edit to the nodes under selections.
+  StructuralEdit {
+    buffer: String,
+    selections: String,
+    op: structural::Op,
+
+    /// Timestamp of the buffer the request was made on.
+    timestamp: u64,
+  },
+
   /// Request to navigate the tree-sitter tree on selections.
   Nav {
     buffer: String,
@@ -99,9 +276,18 @@ pub enum Payload {
 }
 
 /// Possible way of updating a buffer.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum BufferUpdate {
-  /// The full buffer is sent over the buffer FIFO.
+  /// The full buffer is sent over the buffer FIFO, and is reparsed from scratch.
   Full,
+
+  /// The modifications of the buffer are sent over the buffer FIFO, and only the edited parts are reparsed.
+  ///
+  /// Kakoune only knows the modifications made since the last undo group was committed — with
+  /// `%val{uncommitted_modifications}` — so the full buffer is sent instead when another undo group was committed
+  /// since the last update; the edit is then computed by comparing the new content with the previous one. Either
+  /// way, the old tree is edited and reused by the parser, which only has to reparse the parts that have changed.
+  #[default]
+  Incremental,
 }
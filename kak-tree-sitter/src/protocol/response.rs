@@ -4,7 +4,16 @@ use std::{path::PathBuf, sync::mpsc::Sender};
 
 use itertools::Itertools;
 
-use crate::{kakoune::selection::Sel, tree_sitter::highlighting::KakHighlightRange};
+use crate::{
+  kakoune::selection::Sel,
+  tree_sitter::{
+    diagnostics::{Diagnostic, Severity},
+    folds::Fold,
+    highlighting::{HighlightChunk, KakHighlightRange},
+    tags::{self, Symbol},
+    workspace::WorkspaceSymbol,
+  },
+};
 
 /// Response sent from KTS to Kakoune.
 #[derive(Debug, Eq, PartialEq)]
@@ -51,7 +60,8 @@ impl Response {
     };
 
     Some(format!(
-      "evaluate-commands -no-hooks {prefix} -- %[ {payload} ]"
+      "evaluate-commands -no-hooks {prefix} -- {}",
+      quote(&payload)
     ))
   }
 }
@@ -61,8 +71,12 @@ impl Response {
 pub enum Payload {
   /// Initial response when a session starts.
   ///
-  /// This is a list of (language, remove_default_highlighter) configuration.
-  Init { enabled_langs: Vec<(String, bool)> },
+  /// This is a list of (language, remove_default_highlighter) configuration, along with the (face, fallback face) list
+  /// of highlight groups.
+  Init {
+    enabled_langs: Vec<(String, bool)>,
+    faces: Vec<(String, String)>,
+  },
 
   /// Explicit deinit response when the daemon exits.
   ///
@@ -78,26 +92,151 @@ pub enum Payload {
 
     /// Sentinel code used to delimit end of buffers inside the FIFO.
     sentinel: String,
+
+    /// Whether new lines should be indented by KTS; i.e. the language of the buffer has indents.
+    indents: bool,
+
+    /// Whether the breadcrumb should be updated as the cursor moves; i.e. the language of the buffer has context.
+    breadcrumb: bool,
   },
 
+  /// Ask Kakoune to send the whole content of a buffer.
+  ///
+  /// This response is generated when the modifications of a buffer cannot be applied to the buffer known by the
+  /// server.
+  BufferResync,
+
   /// Highlights.
   ///
-  /// This response is generated when new highlights are available.
-  Highlights { ranges: Vec<KakHighlightRange> },
+  /// This response is generated when new highlights are available. Only the chunks that have changed since the last
+  /// highlights are sent.
+  Highlights {
+    /// Timestamp of the buffer the highlights were computed from.
+    timestamp: u64,
+
+    /// Changed chunks.
+    chunks: Vec<HighlightChunk>,
+
+    /// Changed rainbow delimiter chunks.
+    rainbows: Vec<HighlightChunk>,
+  },
+
+  /// Diagnostics of a buffer.
+  ///
+  /// This response is generated after a buffer update, when diagnostics have changed.
+  Diagnostics {
+    /// Timestamp of the buffer the diagnostics were computed from.
+    timestamp: u64,
+
+    diagnostics: Vec<Diagnostic>,
+  },
+
+  /// Definition and references of a symbol.
+  ///
+  /// These are highlighted in the window of the client that asked for them.
+  References {
+    /// Timestamp of the buffer the references were found in.
+    timestamp: u64,
+
+    ranges: Vec<KakHighlightRange>,
+  },
+
+  /// Folds of a buffer.
+  ///
+  /// These replace all the folds of the buffer.
+  Folds {
+    /// Timestamp of the buffer the folds were computed from.
+    timestamp: u64,
+
+    folds: Vec<Fold>,
+  },
+
+  /// Breadcrumb of the context nodes enclosing the cursor.
+  Breadcrumb { breadcrumb: String },
+
+  /// Edits to apply to a buffer, as a single undo group.
+  ///
+  /// Edits are computed for the buffer at a given timestamp, and must not overlap; each one is the selection of the
+  /// range to replace and its text. Once they are applied, the selections are replaced with the given ones, if any;
+  /// those are relative to the buffer once edited.
+  Edits {
+    timestamp: u64,
+    edits: Vec<(Sel, String)>,
+    selections: Vec<Sel>,
+  },
+
+  /// Captures of a query run in the query playground, highlighted in the buffer the query runs against.
+  QueryCaptures {
+    buffer: String,
+    timestamp: u64,
+    ranges: Vec<KakHighlightRange>,
+
+    /// Number of captures per capture name, sorted by name.
+    counts: Vec<(String, usize)>,
+  },
+
+  /// Query of the query playground that could not be compiled.
+  QueryError {
+    buffer: String,
+    timestamp: u64,
+    error: String,
+  },
+
+  /// S-expression of the tree of a buffer.
+  ShowTree { buffer: String, tree: String },
+
+  /// Symbols defined in a buffer.
+  ///
+  /// These are displayed as an outline in the `*tree-sitter-symbols*` buffer.
+  Symbols {
+    /// Buffer the symbols are defined in.
+    buffer: String,
+
+    symbols: Vec<Symbol>,
+  },
+
+  /// Symbols found in a workspace.
+  ///
+  /// These are displayed in the `*tree-sitter-workspace-symbols*` buffer.
+  WorkspaceSymbols {
+    root: PathBuf,
+    symbols: Vec<WorkspaceSymbol>,
+  },
+
+  /// Indentation of lines.
+  ///
+  /// Each line (1-based) of the buffer at the given timestamp comes with the whitespace it should start with.
+  Indents {
+    timestamp: u64,
+    lines: Vec<(usize, String)>,
+  },
 
   /// Selections.
   ///
-  /// These selections are typically returned when the user asked to perform text-objects queries.
-  Selections { sels: Vec<Sel> },
+  /// These selections are typically returned when the user asked to perform text-objects queries. They are relative
+  /// to the buffer at the given timestamp.
+  Selections { timestamp: u64, sels: Vec<Sel> },
 }
 
 impl Payload {
   /// Turn the [`Payload`] into a Kakoune command that can be executed remotely.
   pub fn to_kak(&self) -> String {
     match self {
-      Payload::Init { enabled_langs } => {
-        let add_hl =
-          "add-highlighter -override buffer/tree-sitter-highlighter ranges tree_sitter_hl_ranges";
+      Payload::Init {
+        enabled_langs,
+        faces,
+      } => {
+        let faces = faces
+          .iter()
+          .map(|(face, fallback)| format!("tree-sitter-face-fallback {face} {fallback}"))
+          .join("\n");
+        let add_hl = "add-highlighter -override buffer/tree-sitter-highlighter group";
+        let add_rainbows = "add-highlighter -override buffer/tree-sitter-rainbows group";
+        let add_diagnostics =
+          "add-highlighter -override buffer/tree-sitter-diagnostics ranges tree_sitter_diagnostics_ranges";
+        let add_folds =
+          "add-highlighter -override buffer/tree-sitter-folds replace-ranges tree_sitter_folds";
+        let add_diagnostic_flags = "add-highlighter -override buffer/tree-sitter-diagnostic-flags flag-lines default tree_sitter_diagnostics_flags";
         let per_lang = enabled_langs
           .iter()
           .map(|(lang, remove_default_highlighter)| {
@@ -110,8 +249,13 @@ impl Payload {
             let mut config = format!(
               "hook -group tree-sitter global WinSetOption tree_sitter_lang={lang} %<
                  {remove_default_hl}
+                 tree-sitter-window-update
                  tree-sitter-buffer-metadata
                  {add_hl}
+                 {add_rainbows}
+                 {add_diagnostics}
+                 {add_diagnostic_flags}
+                 {add_folds}
                  tree-sitter-user-after-highlighter
                >",
             );
@@ -125,6 +269,7 @@ impl Payload {
           .join("\n");
 
         [
+          faces,
           per_lang,
           "tree-sitter-hook-install-session".to_owned(),
           "tree-sitter-initial-set-buffer-lang".to_owned(),
@@ -134,9 +279,13 @@ impl Payload {
 
       Payload::Deinit => "tree-sitter-remove-all".to_owned(),
 
+      Payload::BufferResync => "tree-sitter-buffer-resync".to_owned(),
+
       Payload::BufferSetup {
         fifo_path,
         sentinel,
+        indents,
+        breadcrumb,
       } => [
         format!(
           "set-option buffer tree_sitter_buf_fifo_path {}",
@@ -144,31 +293,271 @@ impl Payload {
         ),
         format!("set-option buffer tree_sitter_buf_sentinel {sentinel}"),
         "tree-sitter-hook-install-update".to_owned(),
+        if *indents {
+          "tree-sitter-hook-install-indent".to_owned()
+        } else {
+          String::new()
+        },
+        if *breadcrumb {
+          "tree-sitter-hook-install-breadcrumb".to_owned()
+        } else {
+          String::new()
+        },
       ]
       .into_iter()
       .filter(|s| !s.is_empty())
       .join("\n"),
 
-      Payload::Highlights { ranges } => {
+      Payload::Highlights {
+        timestamp,
+        chunks,
+        rainbows,
+      } => {
+        // highlights and rainbow delimiters are chunked the same way
+        [("hl", chunks), ("rainbow", rainbows)]
+          .into_iter()
+          .flat_map(|(kind, chunks)| {
+            chunks.iter().map(move |HighlightChunk { id, ranges }| {
+              let ranges_str = ranges
+                .iter()
+                .map(KakHighlightRange::to_kak_range_str)
+                .join(" ");
+
+              format!(
+                "tree-sitter-{kind}-declare-chunk {id}\nset-option buffer tree_sitter_{kind}_ranges_{id} {timestamp} {ranges_str}"
+              )
+            })
+          })
+          .join("\n")
+      }
+
+      Payload::Diagnostics {
+        timestamp,
+        diagnostics,
+      } => {
+        let bounds: Vec<_> = diagnostics.iter().map(Diagnostic::kak_bounds).collect();
+
+        // lines are flagged with their most severe diagnostic
+        let flags = bounds
+          .iter()
+          .zip(diagnostics)
+          .map(|((start, _), diag)| (start.line, diag.severity))
+          .into_group_map()
+          .into_iter()
+          .sorted()
+          .map(|(line, severities)| {
+            let severity = severities.into_iter().min().unwrap_or(Severity::Error);
+            format!("{line}|{{ts_diagnostic_{severity}_flag}}●")
+          })
+          .join(" ");
+        let ranges = bounds
+          .iter()
+          .zip(diagnostics)
+          .map(|((start, end), diag)| {
+            format!(
+              "{}.{},{}.{}|ts_diagnostic_{}",
+              start.line, start.col, end.line, end.col, diag.severity
+            )
+          })
+          .join(" ");
+        let entries = bounds
+          .iter()
+          .zip(diagnostics)
+          .map(|((start, end), diag)| {
+            quote(&format!(
+              "{}.{},{}.{}|{}",
+              start.line, start.col, end.line, end.col, diag.message
+            ))
+          })
+          .join(" ");
+
+        [
+          format!("set-option buffer tree_sitter_diagnostics_flags {timestamp} {flags}"),
+          format!("set-option buffer tree_sitter_diagnostics_ranges {timestamp} {ranges}"),
+          format!("set-option buffer tree_sitter_diagnostics {entries}"),
+        ]
+        .join("\n")
+      }
+
+      Payload::References { timestamp, ranges } => {
+        let ranges_str = ranges
+          .iter()
+          .map(KakHighlightRange::to_kak_range_str)
+          .join(" ");
+        format!("set-option window tree_sitter_references {timestamp} {ranges_str}")
+      }
+
+      Payload::Folds { timestamp, folds } => {
+        let folds_str = folds.iter().map(|fold| quote(&fold.to_kak_str())).join(" ");
+        format!("set-option buffer tree_sitter_folds {timestamp} {folds_str}")
+      }
+
+      Payload::Breadcrumb { breadcrumb } => {
+        format!("set-option buffer tree_sitter_breadcrumb {}", quote(breadcrumb))
+      }
+
+      Payload::Edits { edits, .. } if edits.is_empty() => "echo -- 'no edits'".to_owned(),
+
+      Payload::Edits {
+        timestamp,
+        edits,
+        selections,
+      } => {
+        let sels = edits.iter().map(|(sel, _)| sel.to_kak_str()).join(" ");
+        let texts = edits.iter().map(|(_, text)| quote(text)).join(" ");
+        let summary = match edits.len() {
+          1 => "1 edit".to_owned(),
+          n => format!("{n} edits"),
+        };
+
+        // all the selections are replaced at once, so that the edits form a single undo group
+        let replace = [
+          format!("select -timestamp {timestamp} {sels}"),
+          format!("set-register '\"' {texts}"),
+          "execute-keys R".to_owned(),
+          "set-option buffer tree_sitter_edits_timestamp %val{timestamp}".to_owned(),
+        ]
+        .join("\n");
+
+        let select = if selections.is_empty() {
+          String::new()
+        } else {
+          format!(
+            "select -timestamp %opt{{tree_sitter_edits_timestamp}} {}",
+            selections.iter().map(Sel::to_kak_str).join(" ")
+          )
+        };
+
+        [
+          format!("evaluate-commands -draft -save-regs '\"' {}", quote(&replace)),
+          select,
+          format!("echo -- {}", quote(&summary)),
+        ]
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .join("\n")
+      }
+
+      Payload::QueryCaptures {
+        buffer,
+        timestamp,
+        ranges,
+        counts,
+      } => {
         let ranges_str = ranges
           .iter()
           .map(KakHighlightRange::to_kak_range_str)
           .join(" ");
+        let counts_str = counts
+          .iter()
+          .map(|(name, count)| format!("@{name} {count}"))
+          .join(", ");
+        let summary = match ranges.len() {
+          0 => "no captures".to_owned(),
+          1 => format!("1 capture: {counts_str}"),
+          n => format!("{n} captures: {counts_str}"),
+        };
+
+        [
+          format!(
+            "set-option {} tree_sitter_query_captures {timestamp} {ranges_str}",
+            quote(&format!("buffer={buffer}"))
+          ),
+          format!("echo -- {}", quote(&summary)),
+        ]
+        .join("\n")
+      }
 
+      Payload::QueryError {
+        buffer,
+        timestamp,
+        error,
+      } => [
         format!(
-          "{range_specs} %val{{timestamp}} {ranges_str}",
-          range_specs = "set buffer tree_sitter_hl_ranges",
+          "set-option {} tree_sitter_query_captures {timestamp}",
+          quote(&format!("buffer={buffer}"))
+        ),
+        format!("echo -markup -- {}", quote(&format!("{{Error}}{{\\}}{error}"))),
+      ]
+      .join("\n"),
+
+      Payload::ShowTree { buffer, tree } => {
+        format!("tree-sitter-show-tree-open {} {}", quote(buffer), quote(tree))
+      }
+
+      Payload::Symbols { buffer, symbols } => format!(
+        "tree-sitter-symbols-open {} {}",
+        quote(buffer),
+        quote(&tags::outline(symbols))
+      ),
+
+      Payload::WorkspaceSymbols { root, symbols } => {
+        let listing: String = symbols
+          .iter()
+          .map(|WorkspaceSymbol { path, symbol }| {
+            format!(
+              "{path}:{line}:{col}: {kind} {name}\n",
+              path = path.strip_prefix(root).unwrap_or(path).display(),
+              line = symbol.pos.line,
+              col = symbol.pos.col,
+              kind = symbol.kind,
+              name = symbol.name,
+            )
+          })
+          .collect();
+
+        format!(
+          "tree-sitter-workspace-symbols-open {} {}",
+          quote(&root.display().to_string()),
+          quote(&listing)
         )
       }
 
-      Payload::Selections { sels } => {
+      Payload::Indents { timestamp, lines } => lines
+        .iter()
+        .map(|(line, indent)| {
+          let insert = if indent.is_empty() {
+            String::new()
+          } else {
+            format!("; execute-keys \"i{indent}<esc>\"")
+          };
+
+          format!(
+            "evaluate-commands -draft %{{ select -timestamp {timestamp} {line}.1,{line}.1; try %{{ execute-keys -draft x s\\A\\h+<ret>d }}{insert} }}"
+          )
+        })
+        .join("\n"),
+
+      Payload::Selections { timestamp, sels } => {
         let sels_str = sels.iter().map(|sel| sel.to_kak_str()).join(" ");
-        format!("select {sels_str}")
+        format!("select -timestamp {timestamp} {sels_str}")
       }
     }
   }
 }
 
+/// Join the commands of several responses into a single command.
+///
+/// Kakoune stops at the first failing command, so each one runs in its own `try` block; that way, a failing response —
+/// e.g. selecting a stale range — does not drop the ones after it. Errors are logged in the debug buffer.
+pub fn batch(cmds: impl IntoIterator<Item = String>) -> String {
+  cmds
+    .into_iter()
+    .map(|cmd| {
+      format!(
+        "try {} catch {}",
+        quote(&cmd),
+        quote("echo -debug kak-tree-sitter: %val{error}")
+      )
+    })
+    .join("\n")
+}
+
+/// Quote a string for Kakoune, so that it is read as a single word whatever it contains.
+fn quote(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "''"))
+}
+
 /// Add replies to the response queue.
 ///
 /// Response are not immediately sent back to Kakoune, but instead enqueued into
@@ -12,6 +12,7 @@ use std::{
     Arc,
   },
   thread::{spawn, JoinHandle},
+  time::Duration,
 };
 
 use kak_tree_sitter_config::Config;
@@ -25,13 +26,16 @@ use crate::{
   error::OhNo,
   kakoune::{
     buffer::BufferId,
-    selection::Sel,
+    remote::Remote,
+    selection::{Pos, Sel},
     session::{Session, SessionTracker},
+    window::WindowRange,
   },
   protocol::{
     request::{self, Request},
     response::{self, EnqueueResponse, Response},
   },
+  tree_sitter::folds::Fold,
 };
 
 use self::{
@@ -171,6 +175,9 @@ impl IOHandler {
   const WAKE_TKN: Token = Token(0);
   const UNIX_LISTENER_TKN: Token = Token(1);
 
+  /// Idle time after which highlights outside of client windows are sent.
+  const PENDING_HIGHLIGHTS_DELAY: Duration = Duration::from_millis(200);
+
   fn new(
     config: &Config,
     is_standalone: bool,
@@ -211,7 +218,13 @@ impl IOHandler {
 
     log::debug!("starting event loop");
     'event_loop: loop {
-      match self.poll.poll(&mut events, None) {
+      // wake up after a while if some highlights have not been sent yet
+      let timeout = self
+        .handler
+        .has_pending_highlights()
+        .then_some(Self::PENDING_HIGHLIGHTS_DELAY);
+
+      match self.poll.poll(&mut events, timeout) {
         Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
 
         Err(err) => {
@@ -226,6 +239,12 @@ impl IOHandler {
         break 'event_loop;
       }
 
+      if events.is_empty() {
+        for resp in self.handler.handle_pending_highlights() {
+          self.enqueue_response.enqueue(resp);
+        }
+      }
+
       for ev in &events {
         match ev.token() {
           Self::UNIX_LISTENER_TKN if ev.is_readable() => {
@@ -306,7 +325,7 @@ impl IOHandler {
       _ => (),
     }
 
-    if let Err(err) = self.read_buffer(token) {
+    if let Err(err) = self.read_buffer(session_tracker, token) {
       log::error!("error while reading buffer: (token = {token:?}): {err}");
     }
 
@@ -361,6 +380,7 @@ impl IOHandler {
       request::Payload::SessionEnd => {
         log::info!("session {} exit", req.session());
         session_tracker.untrack(req.session());
+        self.handler.handle_session_end(req.session());
 
         // only shutdown if were started with an initial session (non standalone)
         let feedback = if !self.is_standalone && session_tracker.is_empty() {
@@ -383,17 +403,18 @@ impl IOHandler {
         return Ok(Feedback::ShouldExit);
       }
 
-      request::Payload::BufferMetadata { lang } => {
+      request::Payload::BufferMetadata { lang, update } => {
         let buffer = req.buffer().ok_or_else(|| OhNo::UnknownBuffer {
           id: BufferId::new(req.session(), String::new()),
         })?;
 
-        log::info!("buffer metadata {buffer} ({lang})");
+        log::info!("buffer metadata {buffer} ({lang}, {update:?})");
         let id = BufferId::new(req.session(), buffer);
 
-        let resp_payload = self
-          .handler
-          .handle_buffer_metadata(&mut self.resources, &id, lang)?;
+        let resp_payload =
+          self
+            .handler
+            .handle_buffer_metadata(&mut self.resources, &id, lang, *update)?;
         self.enqueue_response.enqueue(req.reply(resp_payload));
       }
 
@@ -405,6 +426,36 @@ impl IOHandler {
         }
       }
 
+      request::Payload::Window { buffer, range } => {
+        let client = req.client().unwrap_or_default();
+        log::debug!("window {range} for client {client}, buffer {buffer}");
+
+        let range = WindowRange::parse_kak_str(range).ok_or_else(|| OhNo::InvalidRequest {
+          req: range.to_owned(),
+          err: "invalid window range".to_owned(),
+        })?;
+        let session =
+          session_tracker
+            .get_mut(req.session())
+            .ok_or_else(|| OhNo::UnknownSession {
+              session: req.session().to_owned(),
+            })?;
+        session.set_client_window(client, buffer, range);
+
+        let id = BufferId::new(req.session(), buffer);
+        if let Some(resp) = self.handler.handle_window(session_tracker, &id) {
+          self.enqueue_response.enqueue(resp);
+        }
+      }
+
+      request::Payload::ClientClose { client } => {
+        log::debug!("client {client} closed");
+
+        if let Some(session) = session_tracker.get_mut(req.session()) {
+          session.remove_client(client);
+        }
+      }
+
       request::Payload::TextObjects {
         buffer,
         pattern,
@@ -422,6 +473,242 @@ impl IOHandler {
         self.enqueue_response.enqueue(req.reply(resp_payload));
       }
 
+      request::Payload::References {
+        buffer,
+        cursor,
+        timestamp,
+      } => {
+        log::info!("references for buffer {buffer}, cursor {cursor}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let cursor = Pos::parse_kak_str(cursor).ok_or_else(|| OhNo::InvalidRequest {
+          req: cursor.to_owned(),
+          err: "invalid cursor position".to_owned(),
+        })?;
+
+        let resp_payload = self.handler.handle_references(&id, cursor)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::SelectReferences {
+        buffer,
+        selections,
+        timestamp,
+      } => {
+        log::info!("select references for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_select_references(&id, &sels)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Fold {
+        buffer,
+        selections,
+        folded,
+        action,
+        timestamp,
+      } => {
+        log::info!("fold for buffer {buffer}, action {action:?}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+        let folded = Fold::parse_many(folded);
+
+        let resp_payload = self.handler.handle_fold(&id, &sels, &folded, *action)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Breadcrumb {
+        buffer,
+        cursor,
+        timestamp,
+      } => {
+        log::debug!("breadcrumb for buffer {buffer}, cursor {cursor}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let cursor = Pos::parse_kak_str(cursor).ok_or_else(|| OhNo::InvalidRequest {
+          req: cursor.to_owned(),
+          err: "invalid cursor position".to_owned(),
+        })?;
+
+        let resp_payload = self.handler.handle_breadcrumb(&id, cursor)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::SelectQuery {
+        buffer,
+        selections,
+        query,
+        capture,
+        timestamp,
+      } => {
+        log::info!("select query capture {capture} for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self
+          .handler
+          .handle_select_query(&id, query, capture, &sels)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Replace {
+        buffer,
+        query,
+        template,
+        timestamp,
+      } => {
+        log::info!("replace with {template} for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+
+        let resp_payload = self.handler.handle_replace(&id, query, template)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::QueryPlayground { buffer, query_path } => {
+        log::info!("query playground for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+
+        let resp_payload = self
+          .handler
+          .handle_query_playground(&id, query_path.as_ref())?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::ShowTree {
+        buffer,
+        selection,
+        scope,
+      } => {
+        log::info!("show tree for buffer {buffer}, scope {scope:?}");
+
+        let id = BufferId::new(req.session(), buffer);
+        let sel = Sel::parse_kak_str(selection).ok_or_else(|| OhNo::InvalidRequest {
+          req: selection.to_owned(),
+          err: "invalid selection".to_owned(),
+        })?;
+
+        let resp_payload = self.handler.handle_show_tree(&id, &sel, *scope)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Symbols { buffer } => {
+        log::info!("symbols for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+
+        let resp_payload = self.handler.handle_symbols(&id)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::WorkspaceIndex { root } => {
+        log::info!("workspace index for {root}");
+        self
+          .handler
+          .handle_workspace_index(req.session(), root.into());
+      }
+
+      request::Payload::WorkspaceSymbols { root, query } => {
+        log::info!("workspace symbols for {root}, query {query}");
+
+        let resp_payload = self
+          .handler
+          .handle_workspace_symbols(req.session(), root.into(), query);
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Indent {
+        buffer,
+        selections,
+        indent_width,
+        timestamp,
+      } => {
+        log::info!("indent for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_indent(&id, &sels, *indent_width)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::DiagnosticNav {
+        buffer,
+        selections,
+        dir,
+        timestamp,
+      } => {
+        log::info!("diagnostic nav for buffer {buffer}, dir {dir:?}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_diagnostic_nav(&id, &sels, *dir)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::Swap {
+        buffer,
+        selections,
+        dir,
+        timestamp,
+      } => {
+        log::info!("swap for buffer {buffer}, dir {dir:?}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_swap(&id, &sels, *dir)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::SplitJoin {
+        buffer,
+        selections,
+        indent_width,
+        timestamp,
+      } => {
+        log::info!("split/join for buffer {buffer}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_split_join(&id, &sels, *indent_width)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
+      request::Payload::StructuralEdit {
+        buffer,
+        selections,
+        op,
+        timestamp,
+      } => {
+        log::info!("structural edit for buffer {buffer}, op {op:?}");
+
+        let id = BufferId::new(req.session(), buffer);
+        self.sync_buffer(session_tracker, &id, *timestamp)?;
+        let sels = Sel::parse_many(selections);
+
+        let resp_payload = self.handler.handle_structural_edit(&id, &sels, op)?;
+        self.enqueue_response.enqueue(req.reply(resp_payload));
+      }
+
       request::Payload::Nav {
         buffer,
         selections,
@@ -440,9 +727,29 @@ impl IOHandler {
     Ok(Feedback::Ok)
   }
 
+  /// Make sure the tree of a buffer is computed from the buffer at the given timestamp.
+  ///
+  /// Kakoune writes a buffer to its FIFO before sending requests that depend on it, but the FIFO might not have been
+  /// read yet; if so, it is read right away. Requests made on another buffer than the one read are rejected.
+  fn sync_buffer(
+    &mut self,
+    session_tracker: &SessionTracker,
+    id: &BufferId,
+    timestamp: u64,
+  ) -> Result<(), OhNo> {
+    for resp in self.handler.sync_buffer(session_tracker, id, timestamp)? {
+      self.enqueue_response.enqueue(resp);
+    }
+
+    self.handler.check_timestamp(id, timestamp)
+  }
+
   /// Read the buffer associated with the argument token.
-  fn read_buffer(&mut self, tkn: Token) -> Result<(), OhNo> {
-    if let Some(resp) = self.handler.handle_full_buffer_update(tkn)? {
+  fn read_buffer(&mut self, session_tracker: &SessionTracker, tkn: Token) -> Result<(), OhNo> {
+    for resp in self
+      .handler
+      .handle_full_buffer_update(session_tracker, tkn)?
+    {
       self.enqueue_response.enqueue(resp);
     }
 
@@ -503,15 +810,117 @@ impl ResponseQueue {
   }
 
   /// Run the response queue by dequeuing connected responses as they arrive in a dedicated thread.
+  ///
+  /// Responses enqueued while sending previous ones are batched, and sent as a single command per session; each
+  /// response runs in its own `try` block, so that a failing one does not drop the others.
   fn run(self) -> JoinHandle<()> {
     spawn(move || {
-      for resp in self.receiver {
-        log::trace!("sending response: {resp:?}");
+      let mut remotes = HashMap::new();
 
-        if let Err(err) = Session::send_response(resp) {
-          log::error!("error while sending connected response: {err}");
-        }
+      while let Ok(resp) = self.receiver.recv() {
+        let resps = std::iter::once(resp).chain(self.receiver.try_iter());
+        Self::send_responses(&mut remotes, resps);
       }
     })
   }
+
+  fn send_responses(
+    remotes: &mut HashMap<String, Remote>,
+    resps: impl IntoIterator<Item = Response>,
+  ) {
+    // commands are grouped by session, keeping the order in which they were enqueued
+    let mut cmds_by_session: Vec<(String, Vec<String>)> = Vec::new();
+    for resp in resps {
+      log::trace!("sending response: {resp:?}");
+
+      let Some(cmd) = resp.to_kak() else {
+        continue;
+      };
+
+      match cmds_by_session
+        .iter_mut()
+        .find(|(session, _)| session == resp.session())
+      {
+        Some((_, cmds)) => cmds.push(cmd),
+        None => cmds_by_session.push((resp.session().to_owned(), vec![cmd])),
+      }
+    }
+
+    for (session, cmds) in cmds_by_session {
+      let remote = remotes
+        .entry(session.clone())
+        .or_insert_with(|| Remote::new(&session));
+
+      if let Err(err) = remote.send_command(&response::batch(cmds)) {
+        log::error!("error while sending connected response: {err}");
+
+        // the session might have moved; forget about it
+        remotes.remove(&session);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, io::Read, os::unix::net::UnixListener, thread};
+
+  use super::ResponseQueue;
+  use crate::{
+    kakoune::{remote::Remote, selection::Sel},
+    protocol::response::{Payload, Response},
+  };
+
+  #[test]
+  fn failing_response_does_not_drop_next_ones() {
+    let socket_path = std::env::temp_dir().join(format!("kts-queue-{}", uuid::Uuid::new_v4()));
+    let listener = UnixListener::bind(&socket_path).unwrap();
+
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut msg = Vec::new();
+      stream.read_to_end(&mut msg).unwrap();
+      msg
+    });
+
+    let mut remotes = HashMap::new();
+    remotes.insert("session".to_owned(), Remote::with_socket_path(&socket_path));
+
+    // selecting a line past the end of the buffer fails in Kakoune
+    let resps = [
+      Response::new(
+        "session",
+        None,
+        None,
+        Payload::Selections {
+          timestamp: 0,
+          sels: vec![Sel::parse_kak_str("100.1,100.1").unwrap()],
+        },
+      ),
+      Response::new(
+        "session",
+        None,
+        None,
+        Payload::Breadcrumb {
+          breadcrumb: "fn main".to_owned(),
+        },
+      ),
+    ];
+    ResponseQueue::send_responses(&mut remotes, resps);
+
+    let msg = server.join().unwrap();
+    std::fs::remove_file(&socket_path).unwrap();
+
+    // skip the header and the length of the command
+    let cmd = String::from_utf8(msg[9..].to_vec()).unwrap();
+    let cmds: Vec<_> = cmd.lines().collect();
+
+    assert_eq!(cmds.len(), 2);
+    for cmd in &cmds {
+      assert!(cmd.starts_with("try 'evaluate-commands "));
+      assert!(cmd.ends_with(" catch 'echo -debug kak-tree-sitter: %val{error}'"));
+    }
+    assert!(cmds[0].contains("select -timestamp 0 100.1,100.1"));
+    assert!(cmds[1].contains("set-option buffer tree_sitter_breadcrumb"));
+  }
 }
@@ -14,10 +14,71 @@ use std::{
 
 use mio::{unix::SourceFd, Interest, Registry, Token};
 
-use crate::error::OhNo;
+use crate::{
+  error::OhNo,
+  kakoune::history::{self, Modification, UncommittedModifications},
+};
 
 use super::tokens::Tokens;
 
+/// Header of a buffer update streamed through a FIFO.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BufferHeader {
+  /// Kakoune timestamp of the buffer.
+  pub timestamp: u64,
+
+  /// Whether the content of the buffer follows the header; otherwise, only its modifications are sent.
+  pub has_content: bool,
+
+  /// Modifications of the buffer, if sent along.
+  pub modifications: Option<UncommittedModifications>,
+}
+
+impl BufferHeader {
+  /// Parse the header of a buffer update, returning it along with what follows it.
+  ///
+  /// The header is either `<timestamp>;`, followed by the content of the buffer, or a list of words quoted with
+  /// `echo -quoting kakoune` and ended with `;`: the timestamp, `full` or `edits` depending on whether the content of
+  /// the buffer follows, `%val{history_id}`, `%val{buf_line_count}` and `%val{uncommitted_modifications}`.
+  fn parse(s: &str) -> Option<(Self, &str)> {
+    if !s.starts_with('\'') {
+      let (timestamp, content) = s.split_once(';')?;
+      let header = Self {
+        timestamp: timestamp.parse().ok()?,
+        has_content: true,
+        modifications: None,
+      };
+
+      return Some((header, content));
+    }
+
+    let (words, content) = history::parse_quoted_words(s)?;
+    let [timestamp, kind, history_id, line_count, modifications @ ..] = words.as_slice() else {
+      return None;
+    };
+    let has_content = match kind.as_str() {
+      "full" => true,
+      "edits" => false,
+      _ => return None,
+    };
+    let modifications = UncommittedModifications {
+      history_id: history_id.parse().ok()?,
+      line_count: line_count.parse().ok()?,
+      modifications: modifications
+        .iter()
+        .map(|modification| Modification::parse_kak_str(modification))
+        .collect::<Option<_>>()?,
+    };
+    let header = Self {
+      timestamp: timestamp.parse().ok()?,
+      has_content,
+      modifications: Some(modifications),
+    };
+
+    Some((header, content))
+  }
+}
+
 #[derive(Debug)]
 pub struct Fifo {
   registry: Arc<Registry>,
@@ -124,7 +185,11 @@ impl Fifo {
     &self.sentinel
   }
 
-  pub fn read_to_buf(&mut self, target: &mut String) -> Result<bool, OhNo> {
+  /// Read from the FIFO, and copy the buffer to `target` once it’s fully read.
+  ///
+  /// Buffers are prefixed by a header — see [`BufferHeader::parse`] — which is returned if a complete buffer was read.
+  /// If the header states that only modifications are sent, `target` is left empty.
+  pub fn read_to_buf(&mut self, target: &mut String) -> Result<Option<BufferHeader>, OhNo> {
     loop {
       match self.file.read_to_string(&mut self.buf) {
         Ok(0) => break,
@@ -151,16 +216,21 @@ impl Fifo {
         sentinel = self.sentinel,
         path = self.path.display()
       );
-      target.clear();
-      target.push_str(&self.buf[..index]);
-
-      log::trace!("new buffer content:\n{target}");
+      let header = BufferHeader::parse(&self.buf[..index]).map(|(header, content)| {
+        target.clear();
+        target.push_str(content);
+        header
+      });
 
       self.buf.drain(..index + self.sentinel.len());
-      return Ok(true);
+
+      let header = header.ok_or(OhNo::InvalidBufferHeader)?;
+      log::trace!("new buffer content ({header:?}):\n{target}");
+
+      return Ok(Some(header));
     }
 
-    Ok(false)
+    Ok(None)
   }
 }
 
@@ -175,3 +245,57 @@ impl Drop for Fifo {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::BufferHeader;
+  use crate::kakoune::{
+    history::{Modification, ModificationKind, UncommittedModifications},
+    selection::Pos,
+  };
+
+  #[test]
+  fn header_parsing() {
+    assert_eq!(
+      BufferHeader::parse("12;fn main() {}\n"),
+      Some((
+        BufferHeader {
+          timestamp: 12,
+          has_content: true,
+          modifications: None,
+        },
+        "fn main() {}\n"
+      ))
+    );
+
+    assert_eq!(
+      BufferHeader::parse("'13' 'edits' '4' '1' '+1.4|x' '-1.1|f';"),
+      Some((
+        BufferHeader {
+          timestamp: 13,
+          has_content: false,
+          modifications: Some(UncommittedModifications {
+            history_id: 4,
+            line_count: 1,
+            modifications: vec![
+              Modification {
+                kind: ModificationKind::Insert,
+                pos: Pos { line: 1, col: 4 },
+                text: "x".to_owned(),
+              },
+              Modification {
+                kind: ModificationKind::Delete,
+                pos: Pos { line: 1, col: 1 },
+                text: "f".to_owned(),
+              },
+            ],
+          }),
+        },
+        ""
+      ))
+    );
+
+    assert_eq!(BufferHeader::parse("'13' 'other' '4' '1';"), None);
+    assert_eq!(BufferHeader::parse("fn main() {}"), None);
+  }
+}
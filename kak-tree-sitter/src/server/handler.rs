@@ -1,11 +1,38 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fs,
+  path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
 use kak_tree_sitter_config::Config;
 use mio::Token;
 
 use crate::{
   error::OhNo,
-  kakoune::{buffer::BufferId, selection::Sel, text_objects::OperationMode},
-  protocol::response::{Payload, Response},
-  tree_sitter::{languages::Languages, nav, state::Trees},
+  kakoune::{
+    buffer::BufferId,
+    faces, rc,
+    selection::{Pos, Sel},
+    session::SessionTracker,
+    text_objects::OperationMode,
+  },
+  protocol::{
+    request::BufferUpdate,
+    response::{Payload, Response},
+  },
+  tree_sitter::{
+    context::BREADCRUMB_SEPARATOR,
+    diagnostics,
+    folds::{self, Fold},
+    highlighting::{HighlightChunk, KakHighlightRange},
+    languages::Languages,
+    nav,
+    playground::{self, QueryCache},
+    state::Trees,
+    structural, swap, syntax_tree,
+    workspace::{IndexedLang, Workspace},
+  },
 };
 
 use super::resources::ServerResources;
@@ -16,6 +43,10 @@ use super::resources::ServerResources;
 /// parsing generates trees/highlighters that can be reused, for instance).
 pub struct Handler {
   trees: Trees,
+
+  // workspaces and ad-hoc queries must be dropped before the languages they use
+  workspaces: HashMap<PathBuf, Workspace>,
+  queries: QueryCache,
   langs: Languages,
   with_highlighting: bool,
 }
@@ -27,6 +58,8 @@ impl Handler {
 
     Ok(Self {
       trees,
+      workspaces: HashMap::default(),
+      queries: QueryCache::default(),
       langs,
       with_highlighting,
     })
@@ -39,7 +72,19 @@ impl Handler {
       .langs()
       .map(|(name, lang)| (name.to_owned(), lang.remove_default_highlighter))
       .collect();
-    Payload::Init { enabled_langs }
+
+    // faces of highlight groups fall back to their parent groups, unless defined by our rc
+    let groups = self
+      .langs
+      .langs()
+      .flat_map(|(_, lang)| lang.hl_config.groups())
+      .collect::<BTreeSet<_>>();
+    let faces = faces::fallbacks(groups, &rc::static_faces());
+
+    Payload::Init {
+      enabled_langs,
+      faces,
+    }
   }
 
   /// Update buffer metadata change.
@@ -48,16 +93,21 @@ impl Handler {
     resources: &mut ServerResources,
     id: &BufferId,
     lang: &str,
+    update: BufferUpdate,
   ) -> Result<Payload, OhNo> {
     let lang = self.langs.get(lang)?;
-    let tree = self.trees.compute(resources, lang, id)?;
+    let tree = self.trees.compute(resources, lang, id, update)?;
     let fifo = tree.fifo();
     let fifo_path = fifo.path().to_owned();
     let sentinel = fifo.sentinel().to_owned();
+    let indents = lang.indent_config.is_some();
+    let breadcrumb = lang.context_config.is_some();
 
     Ok(Payload::BufferSetup {
       fifo_path,
       sentinel,
+      indents,
+      breadcrumb,
     })
   }
 
@@ -67,40 +117,199 @@ impl Handler {
   }
 
   /// Update a full buffer update.
-  pub fn handle_full_buffer_update(&mut self, tkn: Token) -> Result<Option<Response>, OhNo> {
+  ///
+  /// Diagnostics are sent if they have changed. Highlights are prioritized for the lines displayed by the clients of
+  /// the session.
+  pub fn handle_full_buffer_update(
+    &mut self,
+    session_tracker: &SessionTracker,
+    tkn: Token,
+  ) -> Result<Vec<Response>, OhNo> {
     let id = self.trees.get_buf_id(&tkn)?.clone();
     log::debug!("updating {id:?}, token {tkn:?}");
     let tree = self.trees.get_tree_mut(&id)?;
 
     // update the tree
-    if !tree.update_buf()? {
+    match tree.update_buf() {
+      Ok(true) => (),
+
       // early return if no update occurred
-      return Ok(None);
+      Ok(false) => return Ok(Vec::new()),
+
+      Err(OhNo::InvalidBufferModifications) => {
+        log::warn!("cannot apply modifications of {id:?}; asking for the whole buffer");
+        return Ok(vec![Response::new(
+          id.session(),
+          None,
+          id.buffer().to_owned(),
+          Payload::BufferResync,
+        )]);
+      }
+
+      Err(err) => return Err(err),
+    }
+
+    let mut resps = Vec::new();
+    let lang = self.langs.get(tree.lang())?;
+    let timestamp = tree.timestamp();
+
+    if tree.update_diagnostics(lang) {
+      resps.push(Response::new(
+        id.session(),
+        None,
+        id.buffer().to_owned(),
+        Payload::Diagnostics {
+          timestamp,
+          diagnostics: tree.diagnostics().to_vec(),
+        },
+      ));
     }
 
     // run any additional post-processing on the buffer
     if !self.with_highlighting {
-      return Ok(None);
+      return Ok(resps);
     }
 
     // serve highlight
-    let lang = self.langs.get(tree.lang())?;
-    let ranges = tree.highlight(lang, |inject_lang| {
-      self
-        .langs
-        .get(inject_lang)
-        .ok()
-        .map(|lang2| &lang2.hl_config)
-    })?;
+    let viewports = session_tracker.viewports(&id);
+    let (chunks, rainbows) = tree.highlight(
+      lang,
+      |inject_lang| {
+        self
+          .langs
+          .get(inject_lang)
+          .ok()
+          .map(|lang2| &lang2.hl_config)
+      },
+      &viewports,
+    );
+
+    resps.extend(Self::highlights_response(&id, timestamp, chunks, rainbows));
+    Ok(resps)
+  }
+
+  /// Read the FIFO of a buffer until its tree is computed from the buffer at the given timestamp, or until there is
+  /// nothing left to read.
+  ///
+  /// Responses of the buffer updates are returned.
+  pub fn sync_buffer(
+    &mut self,
+    session_tracker: &SessionTracker,
+    id: &BufferId,
+    timestamp: u64,
+  ) -> Result<Vec<Response>, OhNo> {
+    let mut resps = Vec::new();
+
+    loop {
+      let tree = self.trees.get_tree(id)?;
+      let current = tree.timestamp();
+      if current >= timestamp {
+        break;
+      }
+
+      let tkn = *tree.fifo().token();
+      resps.extend(self.handle_full_buffer_update(session_tracker, tkn)?);
+
+      // nothing left to read
+      if self.trees.get_tree(id)?.timestamp() == current {
+        break;
+      }
+    }
+
+    Ok(resps)
+  }
+
+  /// Check that the tree of a buffer is computed from the buffer at the given timestamp.
+  pub fn check_timestamp(&self, id: &BufferId, timestamp: u64) -> Result<(), OhNo> {
+    let current = self.trees.get_tree(id)?.timestamp();
+    if current != timestamp {
+      return Err(OhNo::OutdatedBuffer {
+        id: id.clone(),
+        timestamp,
+        current,
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Handle a client window change.
+  ///
+  /// Pending highlights that are now visible are served right away.
+  pub fn handle_window(
+    &mut self,
+    session_tracker: &SessionTracker,
+    id: &BufferId,
+  ) -> Option<Response> {
+    if !self.with_highlighting {
+      return None;
+    }
+
+    // the window might be known before the buffer is
+    let Ok(tree) = self.trees.get_tree_mut(id) else {
+      return None;
+    };
+
+    let viewports = session_tracker.viewports(id);
+    if viewports.is_empty() {
+      return None;
+    }
+
+    let langs = &self.langs;
+    let lang = langs.get(tree.lang()).ok()?;
+    let (chunks, rainbows) = tree.highlight(
+      lang,
+      |inject_lang| langs.get(inject_lang).ok().map(|lang2| &lang2.hl_config),
+      &viewports,
+    );
+    Self::highlights_response(id, tree.timestamp(), chunks, rainbows)
+  }
+
+  /// Whether some buffers have highlights that have not been sent yet.
+  pub fn has_pending_highlights(&self) -> bool {
+    self.trees.has_pending_highlights()
+  }
+
+  /// Highlight and serve everything that is still pending.
+  pub fn handle_pending_highlights(&mut self) -> Vec<Response> {
+    let langs = &self.langs;
+    self
+      .trees
+      .trees_mut()
+      .filter(|(_, tree)| tree.has_pending_highlights())
+      .flat_map(|(id, tree)| {
+        let lang = langs.get(tree.lang()).ok()?;
+        let (chunks, rainbows) = tree.highlight(
+          lang,
+          |inject_lang| langs.get(inject_lang).ok().map(|lang2| &lang2.hl_config),
+          &[],
+        );
+        Self::highlights_response(id, tree.timestamp(), chunks, rainbows)
+      })
+      .collect()
+  }
+
+  fn highlights_response(
+    id: &BufferId,
+    timestamp: u64,
+    chunks: Vec<HighlightChunk>,
+    rainbows: Vec<HighlightChunk>,
+  ) -> Option<Response> {
+    // nothing to send if no highlight chunk has changed
+    if chunks.is_empty() && rainbows.is_empty() {
+      return None;
+    }
 
-    let resp = Response::new(
+    Some(Response::new(
       id.session(),
       None,
       id.buffer().to_owned(),
-      Payload::Highlights { ranges },
-    );
-
-    Ok(Some(resp))
+      Payload::Highlights {
+        timestamp,
+        chunks,
+        rainbows,
+      },
+    ))
   }
 
   pub fn handle_text_objects(
@@ -116,7 +325,343 @@ impl Handler {
     let lang = self.langs.get(tree_state.lang())?;
     let sels = tree_state.text_objects(lang, pattern, selections, mode)?;
 
-    Ok(Payload::Selections { sels })
+    Ok(Payload::Selections {
+      timestamp: tree_state.timestamp(),
+      sels,
+    })
+  }
+
+  pub fn handle_references(&mut self, id: &BufferId, cursor: Pos) -> Result<Payload, OhNo> {
+    log::debug!("references at {cursor:?} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let ranges = tree_state
+      .local_symbol(lang, cursor)?
+      .map(|symbol| {
+        let definition = symbol
+          .definition
+          .iter()
+          .map(|range| (range, "ts_local_definition"));
+        let references = symbol
+          .references
+          .iter()
+          .map(|range| (range, "ts_local_reference"));
+
+        definition
+          .chain(references)
+          .sorted_by_key(|(range, _)| range.start_byte)
+          .map(|(range, face)| KakHighlightRange::from_ts_range(range, face))
+          .collect()
+      })
+      .unwrap_or_default();
+
+    Ok(Payload::References {
+      timestamp: tree_state.timestamp(),
+      ranges,
+    })
+  }
+
+  pub fn handle_select_references(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+  ) -> Result<Payload, OhNo> {
+    log::debug!("select references for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let sels = tree_state.select_references(lang, selections)?;
+
+    Ok(Payload::Selections {
+      timestamp: tree_state.timestamp(),
+      sels,
+    })
+  }
+
+  pub fn handle_fold(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    folded: &[Fold],
+    action: folds::Action,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("fold {action:?} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let folds = tree_state.fold(lang, selections, folded, action)?;
+
+    Ok(Payload::Folds {
+      timestamp: tree_state.timestamp(),
+      folds,
+    })
+  }
+
+  pub fn handle_breadcrumb(&mut self, id: &BufferId, cursor: Pos) -> Result<Payload, OhNo> {
+    log::debug!("breadcrumb for buffer {id:?}, cursor {cursor:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let labels = tree_state.breadcrumb(lang, cursor)?;
+
+    Ok(Payload::Breadcrumb {
+      breadcrumb: labels.join(BREADCRUMB_SEPARATOR),
+    })
+  }
+
+  pub fn handle_select_query(
+    &mut self,
+    id: &BufferId,
+    query: &str,
+    capture: &str,
+    selections: &[Sel],
+  ) -> Result<Payload, OhNo> {
+    log::debug!("select query capture {capture} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let query = self
+      .queries
+      .get_or_compile(tree_state.lang(), lang.lang(), query)?;
+    let sels = tree_state.select_query(query, capture, selections)?;
+
+    Ok(Payload::Selections {
+      timestamp: tree_state.timestamp(),
+      sels,
+    })
+  }
+
+  pub fn handle_replace(
+    &mut self,
+    id: &BufferId,
+    query: &str,
+    template: &str,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("replace with {template} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let query = self
+      .queries
+      .get_or_compile(tree_state.lang(), lang.lang(), query)?;
+
+    Ok(Payload::Edits {
+      timestamp: tree_state.timestamp(),
+      edits: tree_state.kak_edits(tree_state.replace(query, template)),
+      selections: Vec::new(),
+    })
+  }
+
+  pub fn handle_swap(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    dir: swap::Dir,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("swap {dir:?} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let (edits, selections) = tree_state.swap(selections, dir);
+
+    Ok(Payload::Edits {
+      timestamp: tree_state.timestamp(),
+      edits: tree_state.kak_edits(edits),
+      selections,
+    })
+  }
+
+  pub fn handle_split_join(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    indent_width: usize,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("split/join for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let (edits, selections) = tree_state.split_join(lang, selections, indent_width)?;
+
+    Ok(Payload::Edits {
+      timestamp: tree_state.timestamp(),
+      edits: tree_state.kak_edits(edits),
+      selections,
+    })
+  }
+
+  pub fn handle_structural_edit(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    op: &structural::Op,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("structural edit {op:?} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let (edits, selections) = tree_state.structural_edit(lang, selections, op)?;
+
+    Ok(Payload::Edits {
+      timestamp: tree_state.timestamp(),
+      edits: tree_state.kak_edits(edits),
+      selections,
+    })
+  }
+
+  pub fn handle_query_playground(
+    &mut self,
+    id: &BufferId,
+    query_path: &Path,
+  ) -> Result<Payload, OhNo> {
+    log::debug!(
+      "query playground for buffer {id:?}, query {}",
+      query_path.display()
+    );
+
+    let query = fs::read_to_string(query_path).map_err(|err| OhNo::CannotReadFile {
+      file: query_path.to_owned(),
+      err,
+    })?;
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+
+    let payload = match tree_state.query_captures(lang, &query) {
+      Ok(captures) => Payload::QueryCaptures {
+        buffer: id.buffer().to_owned(),
+        timestamp: tree_state.timestamp(),
+        ranges: captures
+          .iter()
+          .map(|capture| KakHighlightRange::from_ts_range(&capture.range, "ts_query_capture"))
+          .collect(),
+        counts: captures
+          .iter()
+          .map(|capture| capture.name.clone())
+          .counts()
+          .into_iter()
+          .sorted()
+          .collect(),
+      },
+
+      Err(err) => Payload::QueryError {
+        buffer: id.buffer().to_owned(),
+        timestamp: tree_state.timestamp(),
+        error: playground::error_message(&err),
+      },
+    };
+
+    Ok(payload)
+  }
+
+  pub fn handle_show_tree(
+    &mut self,
+    id: &BufferId,
+    sel: &Sel,
+    scope: syntax_tree::Scope,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("show tree for buffer {id:?}, selection {sel:?}, scope {scope:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+
+    Ok(Payload::ShowTree {
+      buffer: id.buffer().to_owned(),
+      tree: tree_state.show_tree(sel, scope),
+    })
+  }
+
+  pub fn handle_symbols(&mut self, id: &BufferId) -> Result<Payload, OhNo> {
+    log::debug!("symbols for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let symbols = tree_state.symbols(lang)?;
+
+    Ok(Payload::Symbols {
+      buffer: id.buffer().to_owned(),
+      symbols,
+    })
+  }
+
+  /// Stop indexing the workspaces that are not used by any session anymore.
+  pub fn handle_session_end(&mut self, session: &str) {
+    self.workspaces.retain(|root, workspace| {
+      workspace.detach(session);
+
+      let is_used = workspace.is_used();
+      if !is_used {
+        log::info!("dropping workspace {}", root.display());
+      }
+
+      is_used
+    });
+  }
+
+  /// Start indexing a workspace for a session, if not already indexed.
+  pub fn handle_workspace_index(&mut self, session: &str, root: PathBuf) -> &Workspace {
+    let langs = &self.langs;
+
+    let workspace = self.workspaces.entry(root).or_insert_with_key(|root| {
+      let indexed_langs = langs
+        .langs()
+        .filter(|(_, lang)| !lang.extensions.is_empty())
+        .filter_map(|(_, lang)| {
+          Some(IndexedLang {
+            ts_lang: lang.lang(),
+            extensions: lang.extensions.clone(),
+            tags_config: lang.tags_config.clone()?,
+          })
+        })
+        .collect();
+
+      Workspace::index(root.clone(), indexed_langs)
+    });
+
+    workspace.attach(session);
+    workspace
+  }
+
+  pub fn handle_workspace_symbols(&mut self, session: &str, root: PathBuf, query: &str) -> Payload {
+    log::debug!("workspace symbols for {}, query {query}", root.display());
+
+    let symbols = self
+      .handle_workspace_index(session, root.clone())
+      .search(query);
+    Payload::WorkspaceSymbols { root, symbols }
+  }
+
+  pub fn handle_indent(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    indent_width: usize,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("indent for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let lang = self.langs.get(tree_state.lang())?;
+    let lines = tree_state.indents(lang, selections, indent_width)?;
+
+    Ok(Payload::Indents {
+      timestamp: tree_state.timestamp(),
+      lines,
+    })
+  }
+
+  pub fn handle_diagnostic_nav(
+    &mut self,
+    id: &BufferId,
+    selections: &[Sel],
+    dir: diagnostics::Dir,
+  ) -> Result<Payload, OhNo> {
+    log::debug!("diagnostic nav {dir:?} for buffer {id:?}");
+
+    let tree_state = self.trees.get_tree(id)?;
+    let sels = diagnostics::nav(tree_state.diagnostics(), selections, dir);
+
+    Ok(Payload::Selections {
+      timestamp: tree_state.timestamp(),
+      sels,
+    })
   }
 
   pub fn handle_nav(
@@ -130,6 +675,9 @@ impl Handler {
     let tree_state = self.trees.get_tree(id)?;
     let sels = tree_state.nav_tree(selections, dir);
 
-    Ok(Payload::Selections { sels })
+    Ok(Payload::Selections {
+      timestamp: tree_state.timestamp(),
+      sels,
+    })
   }
 }
@@ -1,5 +1,20 @@
+pub mod context;
+pub mod diagnostics;
+pub mod edits;
+pub mod folds;
 pub mod highlighting;
+pub mod indents;
 pub mod languages;
+pub mod locals;
 pub mod nav;
+pub mod playground;
 pub mod queries;
+pub mod rainbows;
+pub mod replace;
+pub mod splitjoin;
 pub mod state;
+pub mod structural;
+pub mod swap;
+pub mod syntax_tree;
+pub mod tags;
+pub mod workspace;
@@ -0,0 +1,131 @@
+//! Context of a position.
+//!
+//! The `context.scm` query captures the nodes worth showing in a breadcrumb with `@context`, such as functions or
+//! types, and optionally their names with `@context.name`. A node is labelled with its text up to the end of its name —
+//! e.g. `impl Foo` or `pub fn bar` — or with its first token if it has no name — e.g. `match`.
+
+use std::cmp::Reverse;
+
+use itertools::Itertools;
+use tree_sitter::{Node, Point, Query, QueryCursor, Tree};
+
+use crate::{error::OhNo, tree_sitter::highlighting::satisfies_general_predicates};
+
+/// Separator of the labels of a breadcrumb.
+pub const BREADCRUMB_SEPARATOR: &str = " › ";
+
+/// Context configuration of a language.
+pub struct ContextConfig {
+  query: Query,
+  context_capture: Option<u32>,
+  name_capture: Option<u32>,
+}
+
+impl ContextConfig {
+  /// Create a [`ContextConfig`] from a context query.
+  pub fn new(lang: tree_sitter::Language, context: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, context)?;
+    let context_capture = query.capture_index_for_name("context");
+    let name_capture = query.capture_index_for_name("context.name");
+
+    Ok(Self {
+      query,
+      context_capture,
+      name_capture,
+    })
+  }
+
+  /// Labels of the nodes enclosing a position, from the outermost to the innermost.
+  pub fn breadcrumb(&self, tree: &Tree, source: &str, pos: Point) -> Vec<String> {
+    let mut contexts = Vec::new();
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(pos..Point::new(pos.row, pos.column + 1));
+
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      let context = qm
+        .captures
+        .iter()
+        .find(|capture| Some(capture.index) == self.context_capture)
+        .map(|capture| capture.node);
+      let name = qm
+        .captures
+        .iter()
+        .find(|capture| Some(capture.index) == self.name_capture)
+        .map(|capture| capture.node);
+
+      let Some(context) = context else {
+        continue;
+      };
+
+      if context.start_position() <= pos && pos < context.end_position() {
+        contexts.push((context, name));
+      }
+    }
+
+    contexts.sort_by_key(|(node, _)| (node.start_byte(), Reverse(node.end_byte())));
+    contexts.dedup_by_key(|(node, _)| node.id());
+
+    contexts
+      .into_iter()
+      .filter_map(|(node, name)| label(&node, name.as_ref(), source))
+      .collect()
+  }
+}
+
+/// Label of a context node.
+fn label(node: &Node, name: Option<&Node>, source: &str) -> Option<String> {
+  let end = match name {
+    Some(name) => name.end_byte(),
+    None => {
+      let mut first = *node;
+      while let Some(child) = first.child(0) {
+        first = child;
+      }
+
+      first.end_byte()
+    }
+  };
+
+  let text = source.get(node.start_byte()..end)?;
+  Some(text.split_whitespace().join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Point};
+
+  use super::ContextConfig;
+
+  #[test]
+  fn breadcrumb() {
+    let source = "impl Foo {\n  pub fn bar(&self) {\n    match x {\n      _ => (),\n    }\n  }\n}";
+    let context = r#"
+      (impl_item type: (_) @context.name) @context
+      (function_item name: (identifier) @context.name) @context
+      (match_expression) @context
+    "#;
+    let config = ContextConfig::new(tree_sitter_rust::language(), context).unwrap();
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    assert_eq!(
+      config.breadcrumb(&tree, source, Point::new(3, 6)),
+      vec!["impl Foo", "pub fn bar", "match"]
+    );
+    assert_eq!(
+      config.breadcrumb(&tree, source, Point::new(1, 2)),
+      vec!["impl Foo", "pub fn bar"]
+    );
+    assert_eq!(
+      config.breadcrumb(&tree, source, Point::new(0, 0)),
+      vec!["impl Foo"]
+    );
+  }
+}
@@ -0,0 +1,343 @@
+//! Diagnostics.
+//!
+//! Syntax errors are reported from the ERROR and MISSING nodes of a tree. Lints are reported from the `lints.scm` query,
+//! which captures nodes with `@lint.error`, `@lint.warning`, `@lint.info` or `@lint.hint`, and sets the message of each
+//! pattern with `(#set! message "…")`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Query, QueryCursor, Tree};
+
+use crate::{
+  error::OhNo,
+  kakoune::selection::{Pos, Sel},
+  tree_sitter::highlighting::satisfies_general_predicates,
+};
+
+/// Maximum length of the text of an ERROR node to be quoted in its message.
+const MAX_QUOTED_ERROR_LEN: usize = 32;
+
+/// Severity of a diagnostic, from the most severe to the least.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Severity {
+  Error,
+  Warning,
+  Info,
+  Hint,
+}
+
+impl Severity {
+  /// Parse a [`Severity`] from a `@lint.<severity>` capture name.
+  fn from_capture_name(name: &str) -> Option<Self> {
+    match name.strip_prefix("lint.")? {
+      "error" => Some(Self::Error),
+      "warning" => Some(Self::Warning),
+      "info" => Some(Self::Info),
+      "hint" => Some(Self::Hint),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Error => f.write_str("error"),
+      Self::Warning => f.write_str("warning"),
+      Self::Info => f.write_str("info"),
+      Self::Hint => f.write_str("hint"),
+    }
+  }
+}
+
+/// A diagnostic of a buffer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+  pub range: tree_sitter::Range,
+  pub severity: Severity,
+  pub message: String,
+}
+
+impl Diagnostic {
+  /// Start and end of the diagnostic as Kakoune positions; the end is inclusive.
+  ///
+  /// Empty diagnostics — e.g. missing nodes — cover the character at their position.
+  pub fn kak_bounds(&self) -> (Pos, Pos) {
+    let start: Pos = self.range.start_point.into();
+    let mut end: Pos = self.range.end_point.into();
+
+    if self.range.start_byte == self.range.end_byte {
+      end = start;
+    } else {
+      end.col = end.col.saturating_sub(1).max(1); // kakoune ranges are inclusive
+    }
+
+    (start, end)
+  }
+
+  /// Select the diagnostic.
+  pub fn to_sel(&self) -> Sel {
+    let (anchor, cursor) = self.kak_bounds();
+    Sel { anchor, cursor }
+  }
+}
+
+/// Syntax errors of a tree.
+///
+/// ERROR nodes are reported as a whole, without looking for more errors inside them.
+pub fn syntax_errors(tree: &Tree, source: &str) -> Vec<Diagnostic> {
+  let mut diagnostics = Vec::new();
+  let mut cursor = tree.walk();
+
+  loop {
+    let node = cursor.node();
+
+    if node.is_error() {
+      diagnostics.push(Diagnostic {
+        range: node.range(),
+        severity: Severity::Error,
+        message: error_message(&node, source),
+      });
+    } else if node.is_missing() {
+      diagnostics.push(Diagnostic {
+        range: node.range(),
+        severity: Severity::Error,
+        message: format!("missing `{}`", node.kind()),
+      });
+    }
+
+    // only nodes containing errors are worth visiting
+    if !node.is_error() && node.has_error() && cursor.goto_first_child() {
+      continue;
+    }
+
+    while !cursor.goto_next_sibling() {
+      if !cursor.goto_parent() {
+        return diagnostics;
+      }
+    }
+  }
+}
+
+fn error_message(node: &Node, source: &str) -> String {
+  match node.utf8_text(source.as_bytes()) {
+    Ok(text) if !text.contains('\n') && text.len() <= MAX_QUOTED_ERROR_LEN => {
+      format!("syntax error: unexpected `{text}`")
+    }
+    _ => "syntax error".to_owned(),
+  }
+}
+
+/// Lint configuration of a language.
+pub struct LintConfig {
+  query: Query,
+
+  // severity of each capture, if it is a lint
+  severities: Vec<Option<Severity>>,
+
+  // message of each pattern
+  messages: Vec<Option<String>>,
+}
+
+impl LintConfig {
+  /// Create a [`LintConfig`] from a lints query.
+  pub fn new(lang: tree_sitter::Language, lints: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, lints)?;
+    let severities = query
+      .capture_names()
+      .iter()
+      .map(|name| Severity::from_capture_name(name))
+      .collect();
+    let messages = (0..query.pattern_count())
+      .map(|pattern| {
+        query
+          .property_settings(pattern)
+          .iter()
+          .find(|prop| &*prop.key == "message")
+          .and_then(|prop| prop.value.as_deref().map(str::to_owned))
+      })
+      .collect();
+
+    Ok(Self {
+      query,
+      severities,
+      messages,
+    })
+  }
+
+  /// Lints of a tree.
+  ///
+  /// Patterns without message are reported with the name of their capture.
+  pub fn lints(&self, tree: &Tree, source: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut cursor = QueryCursor::new();
+
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      for capture in qm.captures {
+        let Some(severity) = self.severities[capture.index as usize] else {
+          continue;
+        };
+        let message = self.messages[qm.pattern_index]
+          .clone()
+          .unwrap_or_else(|| format!("lint.{severity}"));
+
+        diagnostics.push(Diagnostic {
+          range: capture.node.range(),
+          severity,
+          message,
+        });
+      }
+    }
+
+    diagnostics
+  }
+}
+
+/// Direction to jump to diagnostics in.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dir {
+  /// Next diagnostic.
+  Next,
+
+  /// Previous diagnostic.
+  Prev,
+}
+
+/// Select the next or previous diagnostic of each selection, wrapping around the buffer.
+///
+/// Selections are kept as-is if there is no diagnostic.
+pub fn nav(diagnostics: &[Diagnostic], selections: &[Sel], dir: Dir) -> Vec<Sel> {
+  let mut sels: Vec<_> = selections
+    .iter()
+    .map(|sel| {
+      let diagnostic = match dir {
+        Dir::Next => diagnostics
+          .iter()
+          .find(|diag| diag.kak_bounds().0 > sel.cursor)
+          .or_else(|| diagnostics.first()),
+        Dir::Prev => diagnostics
+          .iter()
+          .rev()
+          .find(|diag| diag.kak_bounds().0 < sel.cursor)
+          .or_else(|| diagnostics.last()),
+      };
+
+      diagnostic.map_or_else(|| sel.clone(), Diagnostic::to_sel)
+    })
+    .collect();
+  sels.dedup();
+
+  sels
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Tree};
+
+  use super::{nav, syntax_errors, Dir, LintConfig, Severity};
+  use crate::kakoune::selection::{Pos, Sel};
+
+  fn rust_tree(source: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    parser.parse(source, None).unwrap()
+  }
+
+  fn sel(line: usize, col: usize) -> Sel {
+    Sel {
+      anchor: Pos { line, col },
+      cursor: Pos { line, col },
+    }
+  }
+
+  #[test]
+  fn syntax_errors_error_and_missing() {
+    let source = "fn f() {\n  let a = 1\n}\nfn g() { a @@ b; }";
+    let diagnostics = syntax_errors(&rust_tree(source), source);
+    let bounds: Vec<_> = diagnostics
+      .iter()
+      .map(|diag| (diag.kak_bounds(), diag.message.as_str()))
+      .collect();
+
+    assert_eq!(
+      bounds,
+      vec![
+        (
+          (Pos { line: 2, col: 12 }, Pos { line: 2, col: 12 }),
+          "missing `;`"
+        ),
+        (
+          (Pos { line: 4, col: 10 }, Pos { line: 4, col: 13 }),
+          "syntax error: unexpected `a @@`"
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn syntax_errors_nav() {
+    let source = "fn f() {\n  let a = 1\n}\nfn g() { a @@ b; }";
+    let diagnostics = syntax_errors(&rust_tree(source), source);
+
+    assert_eq!(
+      nav(&diagnostics, &[sel(1, 1)], Dir::Next),
+      vec![diagnostics[0].to_sel()]
+    );
+    assert_eq!(
+      nav(&diagnostics, &[sel(3, 1)], Dir::Next),
+      vec![diagnostics[1].to_sel()]
+    );
+    assert_eq!(
+      nav(&diagnostics, &[sel(4, 18)], Dir::Next),
+      vec![diagnostics[0].to_sel()]
+    );
+    assert_eq!(
+      nav(&diagnostics, &[sel(3, 1)], Dir::Prev),
+      vec![diagnostics[0].to_sel()]
+    );
+    assert_eq!(
+      nav(&diagnostics, &[sel(1, 1)], Dir::Prev),
+      vec![diagnostics[1].to_sel()]
+    );
+  }
+
+  #[test]
+  fn lints() {
+    let source = "fn f() { dbg!(1); println!(\"{}\", 2); todo!() }";
+    let lints = r#"
+      ((macro_invocation macro: (identifier) @lint.warning)
+       (#eq? @lint.warning "dbg")
+       (#set! message "dbg! left behind"))
+      ((macro_invocation macro: (identifier) @lint.hint)
+       (#any-of? @lint.hint "todo" "unimplemented"))
+    "#;
+    let config = LintConfig::new(tree_sitter_rust::language(), lints).unwrap();
+    let diagnostics: Vec<_> = config
+      .lints(&rust_tree(source), source)
+      .into_iter()
+      .map(|diag| (diag.kak_bounds(), diag.severity, diag.message))
+      .collect();
+
+    assert_eq!(
+      diagnostics,
+      vec![
+        (
+          (Pos { line: 1, col: 10 }, Pos { line: 1, col: 12 }),
+          Severity::Warning,
+          "dbg! left behind".to_owned()
+        ),
+        (
+          (Pos { line: 1, col: 38 }, Pos { line: 1, col: 41 }),
+          Severity::Hint,
+          "lint.hint".to_owned()
+        ),
+      ]
+    );
+  }
+}
@@ -0,0 +1,152 @@
+//! Edits of buffers.
+//!
+//! Edits are computed by KTS on the tree of a buffer and sent back to Kakoune, which applies them all at once. The
+//! selections to make once they are applied are computed by KTS as well, on the edited text.
+
+use tree_sitter::Range;
+
+use crate::kakoune::selection::{Pos, Sel};
+
+/// Replacement of a range with a text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edit {
+  pub range: Range,
+  pub text: String,
+}
+
+impl Edit {
+  /// Selection of the range to replace in the source the edit was computed on.
+  pub fn sel(&self, source: &str) -> Sel {
+    Sel::from_ts_range(&self.range, source)
+  }
+}
+
+/// Merge the groups of edits computed for several selections.
+///
+/// Each edit comes with whether its inserted text should be selected once the edits are applied. Groups overlapping a
+/// previous group are dropped. Return the edits, sorted by position, and the selections to make once they are applied.
+pub fn merge(
+  source: &str,
+  groups: impl IntoIterator<Item = Vec<(Edit, bool)>>,
+) -> (Vec<Edit>, Vec<Sel>) {
+  let mut edits: Vec<(Edit, bool)> = Vec::new();
+
+  for group in groups {
+    let overlaps = group.iter().any(|(new, _)| {
+      edits.iter().any(|(edit, _)| {
+        new.range.start_byte < edit.range.end_byte && edit.range.start_byte < new.range.end_byte
+      })
+    });
+
+    if !overlaps {
+      edits.extend(group);
+    }
+  }
+
+  edits.sort_by_key(|(edit, _)| edit.range.start_byte);
+  let (edits, selected): (Vec<_>, Vec<_>) = edits.into_iter().unzip();
+  let sels = inserted_sels(source, &edits)
+    .into_iter()
+    .zip(selected)
+    .filter_map(|(sel, selected)| selected.then_some(sel))
+    .collect();
+
+  (edits, sels)
+}
+
+/// Selections of the texts inserted by edits, once applied to a source.
+///
+/// Edits must be sorted by position and must not overlap. Empty texts are selected as a single character at their
+/// position.
+pub fn inserted_sels(source: &str, edits: &[Edit]) -> Vec<Sel> {
+  let mut edited = String::with_capacity(source.len());
+  let mut inserted = Vec::with_capacity(edits.len());
+  let mut prev_end = 0;
+
+  for edit in edits {
+    edited.push_str(&source[prev_end..edit.range.start_byte]);
+    inserted.push((edited.len(), edited.len() + edit.text.len()));
+    edited.push_str(&edit.text);
+    prev_end = edit.range.end_byte;
+  }
+
+  edited.push_str(&source[prev_end..]);
+
+  inserted
+    .into_iter()
+    .map(|(start, end)| Sel {
+      anchor: pos_at(&edited, start),
+      cursor: pos_at(&edited, end.saturating_sub(1).max(start)),
+    })
+    .collect()
+}
+
+/// Kakoune position of a byte offset in a text.
+fn pos_at(text: &str, offset: usize) -> Pos {
+  let before = &text[..offset];
+  let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+  Pos {
+    line: before.matches('\n').count() + 1,
+    col: offset - line_start + 1,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Point, Range};
+
+  use super::{inserted_sels, merge, Edit};
+  use crate::kakoune::selection::Sel;
+
+  fn edit(start_byte: usize, end_byte: usize, text: &str) -> Edit {
+    Edit {
+      // points are not used to apply edits
+      range: Range {
+        start_byte,
+        end_byte,
+        start_point: Point::default(),
+        end_point: Point::default(),
+      },
+      text: text.to_owned(),
+    }
+  }
+
+  #[test]
+  fn inserted_selections() {
+    let source = "f(a, bb);\ng(c);";
+    let edits = [edit(2, 3, "bb"), edit(5, 7, "a"), edit(12, 13, "x\ny")];
+
+    assert_eq!(
+      inserted_sels(source, &edits),
+      vec![
+        Sel::parse_kak_str("1.3,1.4").unwrap(),
+        Sel::parse_kak_str("1.7,1.7").unwrap(),
+        Sel::parse_kak_str("2.3,3.1").unwrap(),
+      ]
+    );
+  }
+
+  #[test]
+  fn merge_groups() {
+    let source = "f(a, b, c);";
+    let groups = [
+      vec![(edit(2, 3, "b"), false), (edit(5, 6, "a"), true)],
+      vec![(edit(5, 6, "c"), false), (edit(8, 9, "b"), true)], // overlaps the first group
+      vec![(edit(8, 9, "cc"), true)],
+    ];
+
+    let (edits, sels) = merge(source, groups);
+    assert_eq!(
+      edits,
+      vec![edit(2, 3, "b"), edit(5, 6, "a"), edit(8, 9, "cc")]
+    );
+    assert_eq!(
+      sels,
+      vec![
+        Sel::parse_kak_str("1.6,1.6").unwrap(),
+        Sel::parse_kak_str("1.9,1.10").unwrap(),
+      ]
+    );
+  }
+}
@@ -0,0 +1,225 @@
+//! Folds.
+//!
+//! The `folds.scm` query captures foldable nodes with `@fold`. Only nodes spanning several lines can be folded; a
+//! folded node keeps its first line visible, and the rest of it is replaced with a placeholder showing how many lines
+//! are hidden.
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Query, QueryCursor, Tree};
+
+use crate::{
+  error::OhNo,
+  kakoune::selection::{Pos, Sel},
+  tree_sitter::highlighting::satisfies_general_predicates,
+};
+
+/// A fold of a buffer.
+///
+/// A fold starts at the end of the first line of a node — on its newline character — and ends with it; both positions
+/// are inclusive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fold {
+  pub start: Pos,
+  pub end: Pos,
+}
+
+impl Fold {
+  /// Parse the folds of a `replace-ranges` option.
+  ///
+  /// Anything that is not a fold, such as the timestamp of the option, is ignored.
+  pub fn parse_many(s: &str) -> Vec<Self> {
+    s.split_whitespace()
+      .flat_map(|word| {
+        let (range, _) = word.trim_start_matches('\'').split_once('|')?;
+        let sel = Sel::parse_kak_str(range)?;
+
+        Some(Self {
+          start: sel.anchor,
+          end: sel.cursor,
+        })
+      })
+      .collect()
+  }
+
+  /// Number of lines hidden by the fold.
+  pub fn hidden_lines(&self) -> usize {
+    self.end.line - self.start.line
+  }
+
+  /// Whether a line is part of the fold, including its first, visible, line.
+  pub fn contains_line(&self, line: usize) -> bool {
+    (self.start.line..=self.end.line).contains(&line)
+  }
+
+  /// Kakoune `replace-ranges` representation.
+  pub fn to_kak_str(self) -> String {
+    let lines = match self.hidden_lines() {
+      1 => "1 line".to_owned(),
+      n => format!("{n} lines"),
+    };
+
+    format!(
+      "{}.{},{}.{}|{{ts_fold}}⋯ {lines}",
+      self.start.line, self.start.col, self.end.line, self.end.col
+    )
+  }
+}
+
+/// Whether to fold or unfold.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+  /// Fold the innermost node that is not folded yet.
+  Fold,
+
+  /// Unfold the folds.
+  Unfold,
+}
+
+/// Fold configuration of a language.
+pub struct FoldConfig {
+  query: Query,
+  fold_capture: Option<u32>,
+}
+
+impl FoldConfig {
+  /// Create a [`FoldConfig`] from a folds query.
+  pub fn new(lang: tree_sitter::Language, folds: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, folds)?;
+    let fold_capture = query.capture_index_for_name("fold");
+
+    Ok(Self {
+      query,
+      fold_capture,
+    })
+  }
+
+  /// Folds of a tree, sorted by position.
+  ///
+  /// Nodes ending at the same position as their first line — e.g. a function and its body — result in a single fold.
+  pub fn folds(&self, tree: &Tree, source: &str) -> Vec<Fold> {
+    let lines: Vec<_> = source.split('\n').collect();
+    let mut folds = Vec::new();
+
+    let mut cursor = QueryCursor::new();
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      for capture in qm.captures {
+        let start = capture.node.start_position();
+        let end = capture.node.end_position();
+
+        if Some(capture.index) != self.fold_capture || start.row >= end.row || end.column == 0 {
+          continue;
+        }
+
+        let Some(first_line) = lines.get(start.row) else {
+          continue;
+        };
+
+        folds.push(Fold {
+          start: Pos {
+            line: start.row + 1,
+            col: first_line.len() + 1,
+          },
+          end: Pos {
+            line: end.row + 1,
+            col: end.column, // kakoune ranges are inclusive
+          },
+        });
+      }
+    }
+
+    folds.sort_by_key(|fold| (fold.start, fold.end));
+    folds.dedup();
+    folds
+  }
+}
+
+/// Fold or unfold the nodes on the lines of the cursors.
+///
+/// Folding a node removes the folds it contains. Unfolding removes every fold containing the line of a cursor.
+pub fn apply(folds: &[Fold], folded: &[Fold], cursors: &[Pos], action: Action) -> Vec<Fold> {
+  let mut folded = folded.to_vec();
+
+  for cursor in cursors {
+    match action {
+      Action::Fold => {
+        let innermost = folds
+          .iter()
+          .filter(|fold| fold.contains_line(cursor.line) && !folded.contains(fold))
+          .min_by_key(|fold| (fold.end.line - fold.start.line, fold.end.col));
+
+        if let Some(fold) = innermost {
+          folded.retain(|inner| !(fold.start <= inner.start && inner.end <= fold.end));
+          folded.push(*fold);
+        }
+      }
+
+      Action::Unfold => folded.retain(|fold| !fold.contains_line(cursor.line)),
+    }
+  }
+
+  folded.sort_by_key(|fold| (fold.start, fold.end));
+  folded
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::{apply, Action, Fold, FoldConfig};
+  use crate::kakoune::selection::Pos;
+
+  fn fold(start: (usize, usize), end: (usize, usize)) -> Fold {
+    Fold {
+      start: Pos {
+        line: start.0,
+        col: start.1,
+      },
+      end: Pos {
+        line: end.0,
+        col: end.1,
+      },
+    }
+  }
+
+  fn cursor(line: usize) -> Pos {
+    Pos { line, col: 1 }
+  }
+
+  #[test]
+  fn fold_nodes() {
+    let source = "fn f() {\n  if a {\n    b();\n  }\n}\nfn g() {}";
+    let folds_query = "[(function_item) (block)] @fold";
+    let config = FoldConfig::new(tree_sitter_rust::language(), folds_query).unwrap();
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+    let folds = config.folds(&tree, source);
+
+    assert_eq!(folds, vec![fold((1, 9), (5, 1)), fold((2, 9), (4, 3))]);
+    assert_eq!(folds[0].to_kak_str(), "1.9,5.1|{ts_fold}⋯ 4 lines");
+
+    // the innermost fold first, then the enclosing one, which replaces it
+    let folded = apply(&folds, &[], &[cursor(3)], Action::Fold);
+    assert_eq!(folded, vec![folds[1]]);
+    let folded = apply(&folds, &folded, &[cursor(2)], Action::Fold);
+    assert_eq!(folded, vec![folds[0]]);
+
+    assert_eq!(apply(&folds, &folded, &[cursor(1)], Action::Unfold), vec![]);
+  }
+
+  #[test]
+  fn parse_folds() {
+    let option = "42 '1.9,5.1|{ts_fold}⋯ 4 lines' '2.9,4.3|{ts_fold}⋯ 2 lines'";
+
+    assert_eq!(
+      Fold::parse_many(option),
+      vec![fold((1, 9), (5, 1)), fold((2, 9), (4, 3))]
+    );
+  }
+}
@@ -1,12 +1,803 @@
-//! Convert from tree-sitter-highlight events to Kakoune ranges highlighter.
+//! Highlighting engine, and conversion from highlights to Kakoune ranges highlighter.
 
-use tree_sitter_highlight::{Highlight, HighlightEvent};
+use std::{
+  cmp::Reverse,
+  collections::{hash_map::Entry, HashMap, HashSet},
+  ops::Range,
+  sync::Arc,
+};
+
+use itertools::Itertools;
+use tree_sitter::{
+  InputEdit, Node, Parser, Point, Query, QueryCursor, QueryMatch, QueryPredicateArg, Tree,
+};
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::{
+  error::OhNo,
+  kakoune::faces,
+  tree_sitter::locals::{Locals, LocalsConfig},
+};
+
+/// Priority of patterns that don’t set one with `(#set! priority <n>)`.
+const DEFAULT_PRIORITY: u32 = 100;
+
+/// Maximum depth of nested injections.
+const MAX_INJECTION_DEPTH: usize = 8;
+
+/// Highlight configuration of a language.
+///
+/// It gathers the queries used to highlight a buffer, along with the face to use for each capture.
+pub struct HighlightConfig {
+  lang: tree_sitter::Language,
+  query: Query,
+
+  // face of each capture, if highlighted
+  faces: Vec<Option<String>>,
+
+  // groups of the highlighted captures
+  groups: Vec<String>,
+
+  // priority of each pattern
+  priorities: Vec<u32>,
+
+  injections: Option<Injections>,
+
+  // local definitions and references, whose faces references inherit
+  locals: Option<Arc<LocalsConfig>>,
+
+  // whether each pattern is disabled for locals, with `(#is-not? local)`
+  non_local_patterns: Vec<bool>,
+
+  // whether nested captures combine their faces
+  layered: bool,
+}
+
+impl HighlightConfig {
+  /// Create a [`HighlightConfig`] from highlights and injections queries.
+  ///
+  /// Every capture of the highlights query is its own group, unless its name starts with `_` or it is filtered out by
+  /// `is_group_highlighted`. Captures not highlighted are ignored.
+  ///
+  /// If `layered` is set, text covered by nested captures is highlighted with the faces of all of them, layered by
+  /// precedence, instead of the face of the capture with the highest precedence only.
+  pub fn new(
+    lang: tree_sitter::Language,
+    highlights: &str,
+    injections: &str,
+    is_group_highlighted: impl Fn(&str) -> bool,
+    layered: bool,
+  ) -> Result<Self, OhNo> {
+    let query = Query::new(lang, highlights)?;
+    let is_highlighted = |name: &&String| !name.starts_with('_') && is_group_highlighted(name);
+    let faces = query
+      .capture_names()
+      .iter()
+      .map(|name| is_highlighted(&name).then(|| faces::group_face(name)))
+      .collect();
+    let groups = query
+      .capture_names()
+      .iter()
+      .filter(is_highlighted)
+      .unique()
+      .cloned()
+      .collect();
+    let priorities = (0..query.pattern_count())
+      .map(|pattern| {
+        query
+          .property_settings(pattern)
+          .iter()
+          .find(|prop| &*prop.key == "priority")
+          .and_then(|prop| prop.value.as_deref()?.parse().ok())
+          .unwrap_or(DEFAULT_PRIORITY)
+      })
+      .collect();
+    let non_local_patterns = (0..query.pattern_count())
+      .map(|pattern| {
+        query
+          .property_predicates(pattern)
+          .iter()
+          .any(|(prop, is_positive)| !is_positive && &*prop.key == "local")
+      })
+      .collect();
+
+    let injections = if injections.trim().is_empty() {
+      None
+    } else {
+      Some(Injections::new(lang, injections)?)
+    };
+
+    Ok(Self {
+      lang,
+      query,
+      faces,
+      groups,
+      priorities,
+      injections,
+      locals: None,
+      non_local_patterns,
+      layered,
+    })
+  }
+
+  /// Resolve local symbols with a locals configuration.
+  ///
+  /// References to local definitions are highlighted with the face of their definition, and patterns with
+  /// `(#is-not? local)` do not apply to them.
+  pub fn with_locals(mut self, locals: Arc<LocalsConfig>) -> Self {
+    self.locals = Some(locals);
+    self
+  }
+
+  /// Groups highlighted by the highlights query.
+  pub fn groups(&self) -> impl Iterator<Item = &str> {
+    self.groups.iter().map(String::as_str)
+  }
+
+  /// Highlight chunks of lines of a buffer, given its already parsed tree.
+  ///
+  /// `chunks` are contiguous, _0-based_ line ranges. Only the text they cover is queried, and the ranges of each chunk
+  /// are returned on their own, cut at the bounds of the chunk. Injected languages are resolved with
+  /// `injection_callback`, and parsed incrementally from the trees of the previous calls, kept in `injection_trees`.
+  pub fn highlight<'a>(
+    &'a self,
+    tree: &Tree,
+    source: &str,
+    injection_trees: &mut InjectionTrees,
+    injection_callback: impl Fn(&str) -> Option<&'a HighlightConfig>,
+    chunks: &[Range<usize>],
+  ) -> Vec<Vec<KakHighlightRange>> {
+    let window = Window::new(source, chunks);
+    let mut spans = Vec::new();
+    self.collect_spans(
+      tree.root_node(),
+      source,
+      &window,
+      0,
+      &injection_callback,
+      injection_trees,
+      &mut spans,
+    );
+    injection_trees.finish(&window.bytes());
+
+    // layers of the same range are kept together, in order, so that Kakoune applies them in that order
+    let ranges = resolve_spans(spans, self.layered, &window.bounds)
+      .into_iter()
+      .flat_map(|(range, faces)| faces.into_iter().map(move |face| (range.clone(), face)));
+    window.kak_ranges(source, ranges)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn collect_spans<'a>(
+    &'a self,
+    root: Node,
+    source: &str,
+    window: &Window,
+    depth: usize,
+    injection_callback: &impl Fn(&str) -> Option<&'a HighlightConfig>,
+    injection_trees: &mut InjectionTrees,
+    spans: &mut Vec<Span<'a>>,
+  ) {
+    let bytes = window.bytes();
+    let locals = self
+      .locals
+      .as_ref()
+      .map(|locals| locals.resolve(root, source, window.points().end))
+      .unwrap_or_default();
+    let local_ranges: HashSet<_> = locals
+      .definitions
+      .iter()
+      .map(|def| &def.range)
+      .chain(
+        locals
+          .references
+          .iter()
+          .filter(|(_, def)| def.is_some())
+          .map(|(reference, _)| &reference.range),
+      )
+      .map(|range| (range.start_byte, range.end_byte))
+      .collect();
+    let layer_start = spans.len();
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(window.points());
+    self.query_spans(&mut cursor, root, source, depth, &local_ranges, spans);
+    let layer_end = spans.len();
+
+    // definitions out of the window still give their faces to the references in it
+    let outside_definitions = locals
+      .references
+      .iter()
+      .filter(|(reference, _)| {
+        reference.range.start_byte < bytes.end && bytes.start < reference.range.end_byte
+      })
+      .filter_map(|(_, def)| *def)
+      .unique()
+      .map(|def| &locals.definitions[def].range)
+      .filter(|range| range.end_byte <= bytes.start || bytes.end <= range.start_byte);
+    for range in outside_definitions {
+      let mut def_spans = Vec::new();
+      let mut cursor = QueryCursor::new();
+      cursor.set_byte_range(range.start_byte..range.end_byte);
+      self.query_spans(
+        &mut cursor,
+        root,
+        source,
+        depth,
+        &local_ranges,
+        &mut def_spans,
+      );
+      spans.extend(
+        def_spans
+          .into_iter()
+          .filter(|span| (span.start, span.end) == (range.start_byte, range.end_byte)),
+      );
+    }
+
+    inherit_local_faces(&locals, &mut spans[layer_start..]);
+    spans.truncate(layer_end);
+
+    let Some(ref injections) = self.injections else {
+      return;
+    };
+
+    if depth >= MAX_INJECTION_DEPTH {
+      log::warn!("too many nested injections; not highlighting them");
+      return;
+    }
+
+    for (lang, ranges) in injections.collect(root, source, window.points()) {
+      let Some(config) = injection_callback(&lang) else {
+        log::trace!("no highlight configuration for injected language {lang}");
+        continue;
+      };
+
+      let mut parser = Parser::new();
+      if let Err(err) = parser.set_language(config.lang) {
+        log::error!("cannot parse injected language {lang}: {err}");
+        continue;
+      }
+
+      if let Err(err) = parser.set_included_ranges(&ranges) {
+        log::error!("invalid ranges for injected language {lang}: {err:?}");
+        continue;
+      }
+
+      let start = ranges[0].start_byte;
+      if let Some(tree) = injection_trees.parse(&mut parser, depth, &lang, start, source) {
+        config.collect_spans(
+          tree.root_node(),
+          source,
+          window,
+          depth + 1,
+          injection_callback,
+          injection_trees,
+          spans,
+        );
+      }
+    }
+  }
+
+  /// Collect the spans of the highlights query matching with a cursor.
+  fn query_spans<'a>(
+    &'a self,
+    cursor: &mut QueryCursor,
+    root: Node,
+    source: &str,
+    depth: usize,
+    local_ranges: &HashSet<(usize, usize)>,
+    spans: &mut Vec<Span<'a>>,
+  ) {
+    for qm in cursor.matches(&self.query, root, source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      let priority = self.priorities[qm.pattern_index];
+      let is_non_local = self.non_local_patterns[qm.pattern_index];
+      for capture in qm.captures {
+        let Some(ref face) = self.faces[capture.index as usize] else {
+          continue;
+        };
+
+        let (start, end) = (capture.node.start_byte(), capture.node.end_byte());
+        if is_non_local && local_ranges.contains(&(start, end)) {
+          continue;
+        }
+
+        if start < end {
+          spans.push(Span {
+            start,
+            end,
+            face,
+            priority,
+            depth,
+            pattern: qm.pattern_index,
+          });
+        }
+      }
+    }
+  }
+}
+
+/// Lines of a buffer highlighted at once, cut into chunks.
+pub(crate) struct Window {
+  lines: Range<usize>,
+
+  // byte at which each chunk starts, followed by the byte at which the last one ends
+  bounds: Vec<usize>,
+}
+
+impl Window {
+  /// Create a [`Window`] covering contiguous, _0-based_ line ranges.
+  pub(crate) fn new(source: &str, chunks: &[Range<usize>]) -> Self {
+    let lines =
+      chunks.first().map_or(0, |chunk| chunk.start)..chunks.last().map_or(0, |chunk| chunk.end);
+    let mut line_starts = source.match_indices('\n').map(|(i, _)| i + 1);
+    let mut line = 0;
+    let mut byte = 0;
+    let bounds = chunks
+      .iter()
+      .map(|chunk| chunk.start)
+      .chain(chunks.last().map(|chunk| chunk.end))
+      .map(|start| {
+        while line < start {
+          byte = line_starts.next().unwrap_or(source.len());
+          line += 1;
+        }
+
+        byte
+      })
+      .collect();
+
+    Self { lines, bounds }
+  }
+
+  pub(crate) fn bytes(&self) -> Range<usize> {
+    self.bounds.first().copied().unwrap_or_default()
+      ..self.bounds.last().copied().unwrap_or_default()
+  }
+
+  pub(crate) fn points(&self) -> Range<Point> {
+    Point::new(self.lines.start, 0)..Point::new(self.lines.end, 0)
+  }
+
+  /// Map ranges of bytes in the window to Kakoune ranges, dispatched into the chunks they start in.
+  ///
+  /// Ranges must be sorted by starting byte.
+  pub(crate) fn kak_ranges(
+    &self,
+    source: &str,
+    ranges: impl IntoIterator<Item = (Range<usize>, impl Into<String>)>,
+  ) -> Vec<Vec<KakHighlightRange>> {
+    let bytes = self.bytes();
+    let chunk_count = self.bounds.len().saturating_sub(1);
+    let mut kak_ranges = vec![Vec::new(); chunk_count];
+    let mut mapper = ByteLineColMapper::new(
+      source[bytes.start..].graphemes(true),
+      bytes.start,
+      self.lines.start + 1,
+    );
+    let mut i = 0;
+    let mut last: Option<(Range<usize>, [usize; 4])> = None;
+
+    for (range, face) in ranges {
+      while i + 1 < chunk_count && self.bounds[i + 1] <= range.start {
+        i += 1;
+      }
+
+      // the same range might come several times, with different faces
+      let [line_start, col_byte_start, line_end, col_byte_end] = match last {
+        Some((ref last_range, pos)) if *last_range == range => pos,
+        _ => {
+          mapper.advance(range.start);
+          let (line_start, col_byte_start) = (mapper.line(), mapper.col_byte());
+
+          mapper.advance(range.end - 1);
+          let pos = [line_start, col_byte_start, mapper.line(), mapper.col_byte()];
+          last = Some((range, pos));
+          pos
+        }
+      };
+
+      kak_ranges[i].push(KakHighlightRange::new(
+        line_start,
+        col_byte_start,
+        line_end,
+        col_byte_end,
+        face,
+      ));
+    }
+
+    kak_ranges
+  }
+}
+
+/// Highlight references to local definitions with the face of their definition.
+///
+/// The face of a definition is the one of the span with the highest precedence covering exactly the definition. Spans
+/// must all come from the same tree.
+fn inherit_local_faces(locals: &Locals, spans: &mut [Span]) {
+  if locals.references.is_empty() {
+    return;
+  }
+
+  let mut by_range: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+  for (i, span) in spans.iter().enumerate() {
+    by_range.entry((span.start, span.end)).or_default().push(i);
+  }
+
+  let definition_faces: Vec<_> = locals
+    .definitions
+    .iter()
+    .map(|def| {
+      by_range
+        .get(&(def.range.start_byte, def.range.end_byte))?
+        .iter()
+        .map(|&i| &spans[i])
+        .max_by_key(|span| span.precedence())
+        .map(|span| span.face)
+    })
+    .collect();
+
+  for (reference, def) in &locals.references {
+    let Some(face) = def.and_then(|def| definition_faces[def]) else {
+      continue;
+    };
+
+    let range = (reference.range.start_byte, reference.range.end_byte);
+    for &i in by_range.get(&range).into_iter().flatten() {
+      spans[i].face = face;
+    }
+  }
+}
+
+/// Trees of the injections of a buffer.
+///
+/// Injections are parsed again on every highlight; keeping their trees around, and editing them along with the tree of
+/// the buffer, allows to parse them incrementally. Trees are matched by depth, language and starting byte, so that the
+/// trees of injections out of the highlighted lines are kept as well.
+#[derive(Debug, Default)]
+pub struct InjectionTrees {
+  trees: HashMap<(usize, String, usize), Tree>,
+
+  // trees parsed by the ongoing highlight
+  parsed: HashSet<(usize, String, usize)>,
+}
+
+impl InjectionTrees {
+  /// Edit the trees according to an edit of the buffer.
+  ///
+  /// Trees of injections starting in the edited text are dropped.
+  pub fn edit(&mut self, edit: &InputEdit) {
+    self.trees = self
+      .trees
+      .drain()
+      .filter_map(|((depth, lang, start), mut tree)| {
+        let start = if start < edit.start_byte {
+          start
+        } else if start >= edit.old_end_byte {
+          start + edit.new_end_byte - edit.old_end_byte
+        } else {
+          return None;
+        };
+
+        tree.edit(edit);
+        Some(((depth, lang, start), tree))
+      })
+      .collect();
+  }
+
+  /// Forget about all the trees; used when the buffer is parsed from scratch.
+  pub fn clear(&mut self) {
+    self.trees.clear();
+    self.parsed.clear();
+  }
+
+  /// Parse an injection of a language, reusing the tree of the previous highlight at the same place, if any.
+  fn parse(
+    &mut self,
+    parser: &mut Parser,
+    depth: usize,
+    lang: &str,
+    start: usize,
+    source: &str,
+  ) -> Option<Tree> {
+    let key = (depth, lang.to_owned(), start);
+    let tree = parser.parse(source, self.trees.get(&key))?;
+    self.trees.insert(key.clone(), tree.clone());
+    self.parsed.insert(key);
+    Some(tree)
+  }
+
+  /// Drop the trees of injections starting in the highlighted bytes that were not parsed by the highlight that just
+  /// ended; those injections are gone.
+  fn finish(&mut self, bytes: &Range<usize>) {
+    let parsed = std::mem::take(&mut self.parsed);
+    self
+      .trees
+      .retain(|key, _| !bytes.contains(&key.2) || parsed.contains(key));
+  }
+}
+
+/// Injection query of a language.
+struct Injections {
+  query: Query,
+  content_capture: Option<u32>,
+  language_capture: Option<u32>,
+}
+
+impl Injections {
+  fn new(lang: tree_sitter::Language, injections: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, injections)?;
+    let content_capture = query.capture_index_for_name("injection.content");
+    let language_capture = query.capture_index_for_name("injection.language");
+
+    Ok(Self {
+      query,
+      content_capture,
+      language_capture,
+    })
+  }
+
+  /// Collect the injected languages intersecting a range of points, along with the ranges they must be parsed on.
+  ///
+  /// Matches of patterns with `(#set! injection.combined)` are gathered in a single injection.
+  fn collect(
+    &self,
+    root: Node,
+    source: &str,
+    points: Range<Point>,
+  ) -> Vec<(String, Vec<tree_sitter::Range>)> {
+    let mut injections: Vec<(String, Vec<tree_sitter::Range>)> = Vec::new();
+    let mut combined: HashMap<usize, usize> = HashMap::new();
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(points);
+    for qm in cursor.matches(&self.query, root, source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      let mut lang = None;
+      let mut content = None;
+      for capture in qm.captures {
+        if Some(capture.index) == self.language_capture {
+          lang = capture.node.utf8_text(source.as_bytes()).ok();
+        } else if Some(capture.index) == self.content_capture {
+          content = Some(capture.node);
+        }
+      }
+
+      let mut include_children = false;
+      let mut is_combined = false;
+      for prop in self.query.property_settings(qm.pattern_index) {
+        match &*prop.key {
+          "injection.language" if lang.is_none() => lang = prop.value.as_deref(),
+          "injection.include-children" => include_children = true,
+          "injection.combined" => is_combined = true,
+          _ => (),
+        }
+      }
+
+      let (Some(lang), Some(content)) = (lang, content) else {
+        continue;
+      };
+      let ranges = content_ranges(content, include_children);
+
+      if !is_combined {
+        injections.push((lang.to_owned(), ranges));
+        continue;
+      }
+
+      match combined.entry(qm.pattern_index) {
+        Entry::Occupied(entry) => injections[*entry.get()].1.extend(ranges),
+        Entry::Vacant(entry) => {
+          entry.insert(injections.len());
+          injections.push((lang.to_owned(), ranges));
+        }
+      }
+    }
+
+    // an empty list of ranges would parse the whole buffer
+    injections.retain(|(_, ranges)| !ranges.is_empty());
+    for (_, ranges) in &mut injections {
+      ranges.sort_by_key(|range| range.start_byte);
+    }
+
+    injections
+  }
+}
+
+/// Ranges of an injected node.
+///
+/// Unless `include_children` is set, the ranges of the children of the node are excluded.
+fn content_ranges(node: Node, include_children: bool) -> Vec<tree_sitter::Range> {
+  let range = node.range();
+  if include_children {
+    return vec![range];
+  }
+
+  let mut ranges = Vec::new();
+  let mut start_byte = range.start_byte;
+  let mut start_point = range.start_point;
+  let mut cursor = node.walk();
+
+  for child in node.children(&mut cursor) {
+    if child.start_byte() > start_byte {
+      ranges.push(tree_sitter::Range {
+        start_byte,
+        end_byte: child.start_byte(),
+        start_point,
+        end_point: child.start_position(),
+      });
+    }
+
+    start_byte = child.end_byte();
+    start_point = child.end_position();
+  }
+
+  if range.end_byte > start_byte {
+    ranges.push(tree_sitter::Range {
+      start_byte,
+      end_byte: range.end_byte,
+      start_point,
+      end_point: range.end_point,
+    });
+  }
+
+  ranges
+}
+
+/// Check the predicates that are not handled by tree-sitter directly.
+///
+/// `#eq?`, `#match?` and their `#not-*` variants are already checked by tree-sitter. Unsupported predicates are
+/// ignored.
+pub(crate) fn satisfies_general_predicates(query: &Query, qm: &QueryMatch, source: &str) -> bool {
+  query
+    .general_predicates(qm.pattern_index)
+    .iter()
+    .all(|predicate| match &*predicate.operator {
+      "any-of?" | "not-any-of?" => {
+        let is_positive = &*predicate.operator == "any-of?";
+        let Some(QueryPredicateArg::Capture(capture_index)) = predicate.args.first() else {
+          return true;
+        };
+        let values: Vec<_> = predicate.args[1..]
+          .iter()
+          .filter_map(|arg| match arg {
+            QueryPredicateArg::String(value) => Some(&**value),
+            QueryPredicateArg::Capture(_) => None,
+          })
+          .collect();
+
+        qm.captures
+          .iter()
+          .filter(|capture| capture.index == *capture_index)
+          .all(|capture| {
+            let text = capture
+              .node
+              .utf8_text(source.as_bytes())
+              .unwrap_or_default();
+            values.contains(&text) == is_positive
+          })
+      }
+
+      _ => true,
+    })
+}
+
+/// A highlighted span of text.
+#[derive(Debug)]
+struct Span<'a> {
+  start: usize,
+  end: usize,
+  face: &'a str,
+  priority: u32,
+
+  // depth of the injection the span comes from
+  depth: usize,
+  pattern: usize,
+}
+
+impl Span<'_> {
+  /// Precedence of a span over the others covering the same text.
+  ///
+  /// Higher priorities come first, then injected spans, then innermost spans; for spans covering the exact same text,
+  /// the first pattern wins.
+  fn precedence(&self) -> (u32, usize, usize, Reverse<usize>, Reverse<usize>) {
+    (
+      self.priority,
+      self.depth,
+      self.start,
+      Reverse(self.end),
+      Reverse(self.pattern),
+    )
+  }
+}
+
+/// Resolve overlapping spans into contiguous, non-overlapping ranges, sorted by position.
+///
+/// Each range gets the face of the span with the highest precedence covering it; if `layered` is set, it gets the
+/// faces of all the spans covering it instead, by increasing precedence. Spans covering the exact same text as a span
+/// of higher precedence are hidden in both cases.
+///
+/// Ranges are clipped to the first and last of the sorted `cuts`, and never cross any of them.
+fn resolve_spans<'a>(
+  mut spans: Vec<Span<'a>>,
+  layered: bool,
+  cuts: &[usize],
+) -> Vec<(Range<usize>, Vec<&'a str>)> {
+  let (Some(&first), Some(&last)) = (cuts.first(), cuts.last()) else {
+    return Vec::new();
+  };
+
+  spans.sort_by_key(|span| span.start);
+
+  let mut bounds: Vec<_> = spans
+    .iter()
+    .flat_map(|span| [span.start, span.end])
+    .map(|bound| bound.clamp(first, last))
+    .chain(cuts.iter().copied())
+    .collect();
+  bounds.sort_unstable();
+  bounds.dedup();
+
+  let mut resolved: Vec<(Range<usize>, Vec<&'a str>)> = Vec::new();
+  let mut active: Vec<&Span> = Vec::new();
+  let mut next = 0;
+
+  for bound in bounds.windows(2) {
+    let (start, end) = (bound[0], bound[1]);
+
+    active.retain(|span| span.end > start);
+    while let Some(span) = spans.get(next).filter(|span| span.start <= start) {
+      active.push(span);
+      next += 1;
+    }
+
+    let faces = if layered {
+      active.sort_by_key(|span| span.precedence());
+      active
+        .iter()
+        .enumerate()
+        .filter(|(i, span)| {
+          !active[i + 1..]
+            .iter()
+            .any(|other| other.start == span.start && other.end == span.end)
+        })
+        .map(|(_, span)| span.face)
+        .dedup()
+        .collect()
+    } else {
+      let Some(span) = active.iter().max_by_key(|span| span.precedence()) else {
+        continue;
+      };
+      vec![span.face]
+    };
+
+    if faces.is_empty() {
+      continue;
+    }
+
+    match resolved.last_mut() {
+      Some((range, prev_faces))
+        if range.end == start && *prev_faces == faces && cuts.binary_search(&start).is_err() =>
+      {
+        range.end = end
+      }
+
+      _ => resolved.push((start..end, faces)),
+    }
+  }
+
+  resolved
+}
+
 /// A convenient representation of a single highlight range for Kakoune.
 ///
 /// `:doc highlighters`, `ranges`, for further documentation.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct KakHighlightRange {
   line_start: usize,
   col_byte_start: usize,
@@ -32,61 +823,15 @@ impl KakHighlightRange {
     }
   }
 
-  /// Given an iterator of [`HighlightEvent`], generate a list of Kakoune highlights.
-  pub fn from_iter(
-    source: &str,
-    hl_names: &[String],
-    hl_events: impl Iterator<Item = HighlightEvent>,
-  ) -> Vec<Self> {
-    let mut kak_hls = Vec::new();
-    let mut faces: Vec<&str> = Vec::new();
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
-
-    // iterate on the highlight event
-    for event in hl_events {
-      match event {
-        HighlightEvent::Source { start, end } => {
-          if start == end {
-            continue;
-          }
-
-          mapper.advance(start);
-          let line_start = mapper.line();
-          let col_byte_start = mapper.col_byte();
-
-          mapper.advance(end - 1);
-          let line_end = mapper.line();
-          let col_byte_end = mapper.col_byte();
-
-          let face = faces.last().copied().unwrap_or("unknown");
-
-          kak_hls.push(KakHighlightRange::new(
-            line_start,
-            col_byte_start,
-            line_end,
-            col_byte_end,
-            format!("ts_{}", face.replace('.', "_")),
-          ));
-        }
-
-        HighlightEvent::HighlightStart(Highlight(idx)) => {
-          if idx >= hl_names.len() {
-            log::error!(
-              "unrecognized highlight group index: {idx} (len: {len}), groups = {hl_names:?}",
-              len = hl_names.len()
-            );
-          } else {
-            faces.push(&hl_names[idx]);
-          }
-        }
-
-        HighlightEvent::HighlightEnd => {
-          faces.pop();
-        }
-      }
-    }
-
-    kak_hls
+  /// Create a [`KakHighlightRange`] covering a tree-sitter range.
+  pub fn from_ts_range(range: &tree_sitter::Range, face: impl Into<String>) -> Self {
+    Self::new(
+      range.start_point.row + 1,
+      range.start_point.column,
+      range.end_point.row + 1,
+      range.end_point.column.saturating_sub(1), // kakoune ranges are inclusive
+      face,
+    )
   }
 
   /// Display as a string recognized by the `ranges` Kakoune highlighter.
@@ -102,9 +847,250 @@ impl KakHighlightRange {
   }
 }
 
+/// Number of lines a highlight chunk covers when created.
+const CHUNK_LINES: usize = 256;
+
+/// Number of lines around viewports highlighted along with them.
+const VIEWPORT_MARGIN_LINES: usize = 64;
+
+/// A chunk of highlight ranges, sent to Kakoune as its own range-specs option.
+///
+/// An empty list of ranges clears the chunk.
+#[derive(Debug, Eq, PartialEq)]
+pub struct HighlightChunk {
+  pub id: usize,
+  pub ranges: Vec<KakHighlightRange>,
+}
+
+/// Line span of a chunk already sent to Kakoune.
+///
+/// Lines are _0-based_, and `end` is exclusive. A dirty chunk must be highlighted and sent again.
+#[derive(Debug, Eq, PartialEq)]
+struct ChunkSpan {
+  id: usize,
+  start: usize,
+  end: usize,
+  dirty: bool,
+}
+
+impl ChunkSpan {
+  fn intersects(&self, lines: &Range<usize>) -> bool {
+    self.start < lines.end && lines.start < self.end
+  }
+}
+
+/// Highlight chunks of a buffer.
+///
+/// Highlighting a buffer doesn’t send all of its ranges back to Kakoune, but splits them in chunks of lines. Each chunk
+/// lives in its own range-specs option, so that only the chunks that have changed need to be sent again after a buffer
+/// update, and only those are highlighted again. Ranges never cross the bounds of a chunk.
+///
+/// Kakoune moves ranges around when a buffer is edited; chunks mimic that by following the same edits, so that chunks
+/// that were not touched by an edit are still valid without sending them again.
+#[derive(Debug, Default)]
+pub struct HighlightChunks {
+  chunks: Vec<ChunkSpan>,
+  next_id: usize,
+  free_ids: Vec<usize>,
+  retired_ids: Vec<usize>,
+}
+
+impl HighlightChunks {
+  fn new_id(&mut self) -> usize {
+    self.free_ids.pop().unwrap_or_else(|| {
+      let id = self.next_id;
+      self.next_id += 1;
+      id
+    })
+  }
+
+  /// Invalidate every chunk; they will all be sent again.
+  pub fn invalidate_all(&mut self) {
+    self
+      .retired_ids
+      .extend(self.chunks.drain(..).map(|chunk| chunk.id));
+  }
+
+  /// Invalidate chunks touching the given lines.
+  pub fn invalidate_lines(&mut self, lines: Range<usize>) {
+    for chunk in &mut self.chunks {
+      if chunk.intersects(&lines) {
+        chunk.dirty = true;
+      }
+    }
+  }
+
+  /// Move chunks around according to an edit, the same way Kakoune would move ranges around.
+  ///
+  /// Chunks after the edit are shifted, and chunks overlapping with the edit are merged and invalidated.
+  pub fn apply_edit(&mut self, edit: &InputEdit) {
+    let start = edit.start_position.row;
+    let old_end = edit.old_end_position.row;
+    let new_end = edit.new_end_position.row;
+    let shift = |line: usize| (line + new_end).saturating_sub(old_end);
+
+    let mut chunks = Vec::with_capacity(self.chunks.len());
+    let mut merged: Option<ChunkSpan> = None;
+
+    for mut chunk in self.chunks.drain(..) {
+      if chunk.end <= start {
+        chunks.push(chunk);
+      } else if chunk.start > old_end {
+        chunk.start = shift(chunk.start);
+        chunk.end = shift(chunk.end);
+        chunks.extend(merged.take());
+        chunks.push(chunk);
+      } else if let Some(ref mut merged) = merged {
+        merged.end = shift(chunk.end).max(new_end + 1);
+        self.retired_ids.push(chunk.id);
+      } else {
+        chunk.end = shift(chunk.end).max(new_end + 1);
+        chunk.dirty = true;
+        merged = Some(chunk);
+      }
+    }
+
+    chunks.extend(merged);
+    self.chunks = chunks;
+  }
+
+  /// Make the chunks cover exactly `line_count` lines, splitting chunks that have grown too big.
+  fn normalize(&mut self, line_count: usize) {
+    // remove chunks that are past the end of the buffer
+    while let Some(chunk) = self.chunks.last() {
+      if self.chunks.len() > 1 && chunk.start >= line_count {
+        let id = chunk.id;
+        self.retired_ids.push(id);
+        self.chunks.pop();
+      } else {
+        break;
+      }
+    }
+
+    match self.chunks.last_mut() {
+      Some(chunk) => {
+        if chunk.end != line_count {
+          chunk.end = line_count;
+          chunk.dirty = true;
+        }
+      }
+
+      None => {
+        let id = self.new_id();
+        self.chunks.push(ChunkSpan {
+          id,
+          start: 0,
+          end: line_count,
+          dirty: true,
+        });
+      }
+    }
+
+    let mut chunks = Vec::with_capacity(self.chunks.len());
+    for chunk in std::mem::take(&mut self.chunks) {
+      if chunk.end - chunk.start <= 2 * CHUNK_LINES {
+        chunks.push(chunk);
+        continue;
+      }
+
+      let mut start = chunk.start;
+      while start < chunk.end {
+        let id = if start == chunk.start {
+          chunk.id
+        } else {
+          self.new_id()
+        };
+        let end = if chunk.end - start <= 2 * CHUNK_LINES {
+          chunk.end
+        } else {
+          start + CHUNK_LINES
+        };
+
+        chunks.push(ChunkSpan {
+          id,
+          start,
+          end,
+          dirty: true,
+        });
+
+        start = end;
+      }
+    }
+
+    self.chunks = chunks;
+  }
+
+  /// Whether some invalidated chunks have not been highlighted yet.
+  pub fn has_pending(&self) -> bool {
+    !self.retired_ids.is_empty() || self.chunks.iter().any(|chunk| chunk.dirty)
+  }
+
+  /// Highlight the invalidated chunks of a buffer of `line_count` lines, and return them.
+  ///
+  /// `highlight` computes the ranges of runs of contiguous chunks, given their line ranges. The ranges of the other
+  /// chunks are still valid in Kakoune, and are not computed again. Retired chunks are returned as well, and are empty.
+  ///
+  /// If `viewports` is not empty, only the invalidated chunks around those lines are highlighted; the other ones are
+  /// kept invalidated, and will be highlighted by a later call.
+  pub fn refresh(
+    &mut self,
+    line_count: usize,
+    viewports: &[Range<usize>],
+    mut highlight: impl FnMut(&[Range<usize>]) -> Vec<Vec<KakHighlightRange>>,
+  ) -> Vec<HighlightChunk> {
+    self.normalize(line_count);
+
+    let mut hl_chunks: Vec<_> = self
+      .retired_ids
+      .drain(..)
+      .map(|id| {
+        self.free_ids.push(id);
+        HighlightChunk {
+          id,
+          ranges: Vec::new(),
+        }
+      })
+      .collect();
+
+    let is_visible = |chunk: &ChunkSpan| {
+      viewports.is_empty()
+        || viewports.iter().any(|lines| {
+          chunk.start < lines.end + VIEWPORT_MARGIN_LINES
+            && lines.start.saturating_sub(VIEWPORT_MARGIN_LINES) < chunk.end
+        })
+    };
+
+    let mut i = 0;
+    while i < self.chunks.len() {
+      if !self.chunks[i].dirty || !is_visible(&self.chunks[i]) {
+        i += 1;
+        continue;
+      }
+
+      // contiguous chunks are highlighted at once
+      let run_start = i;
+      while i < self.chunks.len() && self.chunks[i].dirty && is_visible(&self.chunks[i]) {
+        i += 1;
+      }
+
+      let run = &mut self.chunks[run_start..i];
+      let lines: Vec<_> = run.iter().map(|chunk| chunk.start..chunk.end).collect();
+      for (chunk, ranges) in run.iter_mut().zip(highlight(&lines)) {
+        chunk.dirty = false;
+        hl_chunks.push(HighlightChunk {
+          id: chunk.id,
+          ranges,
+        });
+      }
+    }
+
+    hl_chunks
+  }
+}
+
 /// Map byte indices to line and column.
 #[derive(Debug)]
-struct ByteLineColMapper<C> {
+pub(crate) struct ByteLineColMapper<C> {
   chars: C,
   byte_idx: usize,
   line: usize,
@@ -115,20 +1101,23 @@ impl<'a, C> ByteLineColMapper<C>
 where
   C: Iterator<Item = &'a str>,
 {
-  fn new(chars: C) -> Self {
+  /// Create a mapper whose `chars` start at the beginning of a line, at a given byte.
+  ///
+  /// Lines are _1-based_.
+  pub(crate) fn new(chars: C, byte_idx: usize, line: usize) -> Self {
     Self {
       chars,
-      byte_idx: 0,
-      line: 1,
+      byte_idx,
+      line,
       col_byte: 0,
     }
   }
 
-  fn line(&self) -> usize {
+  pub(crate) fn line(&self) -> usize {
     self.line
   }
 
-  fn col_byte(&self) -> usize {
+  pub(crate) fn col_byte(&self) -> usize {
     self.col_byte
   }
 
@@ -137,14 +1126,14 @@ where
   }
 
   /// Advance the mapper until the given byte is read (or just passed over).
-  fn advance(&mut self, til: usize) {
+  pub(crate) fn advance(&mut self, til: usize) {
     loop {
       if self.byte_idx >= til {
         break;
       }
 
       if let Some(grapheme) = self.chars.next() {
-        let bytes = grapheme.as_bytes().len();
+        let bytes = grapheme.len();
         self.byte_idx += bytes;
 
         if Self::should_change_line(grapheme) {
@@ -162,15 +1151,21 @@ where
 
 #[cfg(test)]
 mod tests {
-  use tree_sitter_highlight::{Highlight, HighlightConfiguration, HighlightEvent, Highlighter};
+  use std::{ops::Range, slice, sync::Arc};
+
+  use tree_sitter::{InputEdit, Parser, Point, Tree};
   use unicode_segmentation::UnicodeSegmentation;
 
-  use super::ByteLineColMapper;
+  use super::{
+    ByteLineColMapper, HighlightChunk, HighlightChunks, HighlightConfig, InjectionTrees,
+    KakHighlightRange,
+  };
+  use crate::tree_sitter::locals::LocalsConfig;
 
   #[test]
   fn idempotent_mapper() {
     let source = "Hello, world!";
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true), 0, 1);
 
     assert_eq!(mapper.line(), 1);
     assert_eq!(mapper.col_byte(), 0);
@@ -193,7 +1188,7 @@ mod tests {
   #[test]
   fn lines_mapper() {
     let source = "const x: &'str = \"Hello, world!\";\nconst y = 3;";
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true), 0, 1);
 
     assert_eq!(mapper.line(), 1);
     assert_eq!(mapper.col_byte(), 0);
@@ -218,7 +1213,7 @@ mod tests {
   #[test]
   fn unicode_mapper() {
     let source = "const ᾩ = 1"; // the unicode symbol is 3-bytes
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true), 0, 1);
 
     assert_eq!(mapper.line(), 1);
     assert_eq!(mapper.col_byte(), 0);
@@ -251,7 +1246,7 @@ mod tests {
   #[test]
   fn unicode_mapper_more() {
     let source = "× a"; // 2 bytes
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true), 0, 1);
 
     mapper.advance(1);
     assert_eq!(mapper.line(), 1);
@@ -261,7 +1256,7 @@ mod tests {
   #[test]
   fn newline_mapper() {
     let source = "×\na"; // 2 bytes, 1 byte, 1 byte
-    let mut mapper = ByteLineColMapper::new(source.graphemes(true));
+    let mut mapper = ByteLineColMapper::new(source.graphemes(true), 0, 1);
 
     assert_eq!(mapper.line(), 1);
     assert_eq!(mapper.col_byte(), 0);
@@ -279,111 +1274,465 @@ mod tests {
     assert_eq!(mapper.col_byte(), 0);
   }
 
+  fn rust_tree(source: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    parser.parse(source, None).unwrap()
+  }
+
+  fn kak_ranges_str(ranges: &[KakHighlightRange]) -> Vec<String> {
+    ranges
+      .iter()
+      .map(KakHighlightRange::to_kak_range_str)
+      .collect()
+  }
+
   #[test]
-  fn kak_hl_ranges_from_iter() {
+  fn highlight_rust() {
     let source = "fn foo(a: i32, b: /* ® */ impl Into<Option<String>>) {}";
-    let hl_names = vec![
-      "constant",
-      "function",
-      "keyword",
-      "variable",
-      "punctuation",
-      "type",
-      "comment",
-    ];
-
-    let mut hl_conf = HighlightConfiguration::new(
+    let config = HighlightConfig::new(
       tree_sitter_rust::language(),
       tree_sitter_rust::HIGHLIGHT_QUERY,
       tree_sitter_rust::INJECTIONS_QUERY,
+      |_| true,
+      false,
+    )
+    .unwrap();
+
+    let ranges = config.highlight(
+      &rust_tree(source),
+      source,
+      &mut InjectionTrees::default(),
+      |_| None,
+      slice::from_ref(&(0..1)),
+    );
+
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec![
+        "1.1,1.2|ts_keyword",
+        "1.4,1.6|ts_function",
+        "1.7,1.7|ts_punctuation_bracket",
+        "1.8,1.8|ts_variable_parameter",
+        "1.9,1.9|ts_punctuation_delimiter",
+        "1.11,1.13|ts_type_builtin",
+        "1.14,1.14|ts_punctuation_delimiter",
+        "1.16,1.16|ts_variable_parameter",
+        "1.17,1.17|ts_punctuation_delimiter",
+        "1.19,1.26|ts_comment",
+        "1.28,1.31|ts_keyword",
+        "1.33,1.36|ts_type",
+        "1.37,1.37|ts_punctuation_bracket",
+        "1.38,1.43|ts_type",
+        "1.44,1.44|ts_punctuation_bracket",
+        "1.45,1.50|ts_type",
+        "1.51,1.53|ts_punctuation_bracket",
+        "1.55,1.56|ts_punctuation_bracket",
+      ]
+    );
+  }
+
+  #[test]
+  fn discovered_groups() {
+    let highlights = r#"
+      (identifier) @variable.parameter
+      (primitive_type) @type.builtin
+      (line_comment) @comment
+      (block_comment) @_private
+    "#;
+    let config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      highlights,
       "",
+      |group| !group.starts_with("comment"),
+      false,
     )
     .unwrap();
-    hl_conf.configure(&hl_names);
 
-    let mut hl = Highlighter::new();
-    let events: Vec<_> = hl
-      .highlight(&hl_conf, source.as_bytes(), None, |_| None)
-      .unwrap()
-      .flatten()
-      .collect();
+    assert_eq!(
+      config.groups().collect::<Vec<_>>(),
+      vec!["variable.parameter", "type.builtin"]
+    );
+  }
+
+  #[test]
+  fn highlight_predicates_and_priorities() {
+    let source = "let foo = baz + qux;";
+    let highlights = r#"
+      ((identifier) @keyword (#any-of? @keyword "foo" "bar"))
+      ((identifier) @variable (#not-eq? @variable "qux"))
+      ((identifier) @constant (#match? @constant "^b") (#set! priority 110))
+    "#;
+    let config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      highlights,
+      "",
+      |_| true,
+      false,
+    )
+    .unwrap();
+
+    let ranges = config.highlight(
+      &rust_tree(source),
+      source,
+      &mut InjectionTrees::default(),
+      |_| None,
+      slice::from_ref(&(0..1)),
+    );
+
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec!["1.5,1.7|ts_keyword", "1.11,1.13|ts_constant"]
+    );
+  }
+
+  #[test]
+  fn highlight_layered() {
+    let source = r#"let a = "x\ny";"#;
+    let highlights = r#"
+      ((identifier) @constant (#eq? @constant "a"))
+      (identifier) @variable
+      (string_literal) @string
+      (escape_sequence) @constant.character.escape
+    "#;
+    let config =
+      HighlightConfig::new(tree_sitter_rust::language(), highlights, "", |_| true, true).unwrap();
+
+    let ranges = config.highlight(
+      &rust_tree(source),
+      source,
+      &mut InjectionTrees::default(),
+      |_| None,
+      slice::from_ref(&(0..1)),
+    );
+
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec![
+        "1.5,1.5|ts_constant",
+        "1.9,1.10|ts_string",
+        "1.11,1.12|ts_string",
+        "1.11,1.12|ts_constant_character_escape",
+        "1.13,1.14|ts_string",
+      ]
+    );
+  }
+
+  #[test]
+  fn highlight_injections() {
+    let source = "foo!(bar); baz!(qux);";
+    let injections = r#"
+      ((macro_invocation (token_tree) @injection.content)
+       (#set! injection.language "inner")
+       (#set! injection.include-children)
+       (#set! injection.combined))
+    "#;
+    let config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      "(identifier) @variable",
+      injections,
+      |_| true,
+      false,
+    )
+    .unwrap();
+    let inner_config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      "(identifier) @function",
+      "",
+      |_| true,
+      false,
+    )
+    .unwrap();
+
+    let injection_callback = |lang: &str| (lang == "inner").then_some(&inner_config);
+    let mut tree = rust_tree(source);
+    let mut injection_trees = InjectionTrees::default();
+    let ranges = config.highlight(
+      &tree,
+      source,
+      &mut injection_trees,
+      injection_callback,
+      slice::from_ref(&(0..1)),
+    );
+
+    // injected highlights win over the ones of the host language
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec![
+        "1.1,1.3|ts_variable",
+        "1.6,1.8|ts_function",
+        "1.12,1.14|ts_variable",
+        "1.17,1.19|ts_function",
+      ]
+    );
+    assert_eq!(
+      injection_trees.trees.keys().collect::<Vec<_>>(),
+      vec![&(0, "inner".to_owned(), 4)]
+    );
+
+    // the injection is parsed again from its edited tree
+    let source = "foo!(barx); baz!(qux);";
+    let edit = InputEdit {
+      start_byte: 8,
+      old_end_byte: 8,
+      new_end_byte: 9,
+      start_position: Point::new(0, 8),
+      old_end_position: Point::new(0, 8),
+      new_end_position: Point::new(0, 9),
+    };
+    tree.edit(&edit);
+    injection_trees.edit(&edit);
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, Some(&tree)).unwrap();
+    let ranges = config.highlight(
+      &tree,
+      source,
+      &mut injection_trees,
+      injection_callback,
+      slice::from_ref(&(0..1)),
+    );
+
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec![
+        "1.1,1.3|ts_variable",
+        "1.6,1.9|ts_function",
+        "1.13,1.15|ts_variable",
+        "1.18,1.20|ts_function",
+      ]
+    );
+    assert_eq!(
+      injection_trees.trees.keys().collect::<Vec<_>>(),
+      vec![&(0, "inner".to_owned(), 4)]
+    );
+  }
+
+  #[test]
+  fn highlight_locals() {
+    let source = "fn f(a: i32) { a + b }";
+    let highlights = r#"
+      (parameter pattern: (identifier) @variable.parameter)
+      ((identifier) @constant (#is-not? local) (#set! priority 110))
+      (identifier) @variable
+    "#;
+    let locals = r#"
+      [(function_item) (block)] @local.scope
+      (parameter pattern: (identifier) @local.definition)
+      (identifier) @local.reference
+    "#;
+    let locals = LocalsConfig::new(tree_sitter_rust::language(), locals).unwrap();
+    let config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      highlights,
+      "",
+      |_| true,
+      false,
+    )
+    .unwrap()
+    .with_locals(Arc::new(locals));
+
+    let ranges = config.highlight(
+      &rust_tree(source),
+      source,
+      &mut InjectionTrees::default(),
+      |_| None,
+      slice::from_ref(&(0..1)),
+    );
+
+    // the reference to the parameter gets its face, and is not a constant; unresolved references are
+    assert_eq!(
+      kak_ranges_str(&ranges[0]),
+      vec![
+        "1.4,1.4|ts_constant",
+        "1.6,1.6|ts_variable_parameter",
+        "1.16,1.16|ts_variable_parameter",
+        "1.20,1.20|ts_constant",
+      ]
+    );
+  }
+
+  #[test]
+  fn highlight_chunks() {
+    let source = "fn f(a: i32) {\n  /* x\n  y */\n  a\n}\n";
+    let highlights = r#"
+      (parameter pattern: (identifier) @variable.parameter)
+      (identifier) @variable
+      (block_comment) @comment
+    "#;
+    let locals = r#"
+      [(function_item) (block)] @local.scope
+      (parameter pattern: (identifier) @local.definition)
+      (identifier) @local.reference
+    "#;
+    let locals = LocalsConfig::new(tree_sitter_rust::language(), locals).unwrap();
+    let config = HighlightConfig::new(
+      tree_sitter_rust::language(),
+      highlights,
+      "",
+      |_| true,
+      false,
+    )
+    .unwrap()
+    .with_locals(Arc::new(locals));
+
+    let ranges = config.highlight(
+      &rust_tree(source),
+      source,
+      &mut InjectionTrees::default(),
+      |_| None,
+      &[2..3, 3..5],
+    );
+
+    // the comment is cut at the bounds of the chunks, and the reference still gets the face of its definition
+    assert_eq!(
+      ranges.iter().map(|r| kak_ranges_str(r)).collect::<Vec<_>>(),
+      vec![
+        vec!["3.1,3.6|ts_comment"],
+        vec!["4.3,4.3|ts_variable_parameter"],
+      ]
+    );
+  }
+
+  /// One range per line of each chunk.
+  fn line_ranges(chunks: &[Range<usize>]) -> Vec<Vec<KakHighlightRange>> {
+    chunks
+      .iter()
+      .map(|lines| {
+        (lines.start + 1..=lines.end)
+          .map(|line| KakHighlightRange::new(line, 0, line, 1, "ts_keyword"))
+          .collect()
+      })
+      .collect()
+  }
+
+  fn line_edit(start: usize, old_end: usize, new_end: usize) -> InputEdit {
+    InputEdit {
+      start_byte: 0,
+      old_end_byte: 0,
+      new_end_byte: 0,
+      start_position: Point::new(start, 0),
+      old_end_position: Point::new(old_end, 0),
+      new_end_position: Point::new(new_end, 0),
+    }
+  }
+
+  fn chunk_ids(chunks: &[HighlightChunk]) -> Vec<(usize, usize)> {
+    chunks
+      .iter()
+      .map(|chunk| (chunk.id, chunk.ranges.len()))
+      .collect()
+  }
+
+  #[test]
+  fn hl_chunks_initial_refresh() {
+    let mut chunks = HighlightChunks::default();
+    let hl_chunks = chunks.refresh(600, &[], line_ranges);
+
+    assert_eq!(chunk_ids(&hl_chunks), vec![(0, 256), (1, 344)]);
+
+    // nothing changed, so nothing to send
+    let hl_chunks = chunks.refresh(600, &[], line_ranges);
+    assert!(hl_chunks.is_empty());
+  }
+
+  #[test]
+  fn hl_chunks_edit_in_line() {
+    let mut chunks = HighlightChunks::default();
+    chunks.refresh(600, &[], line_ranges);
+
+    chunks.apply_edit(&line_edit(300, 300, 300));
+    chunks.invalidate_lines(300..301);
+    let hl_chunks = chunks.refresh(600, &[], line_ranges);
+
+    assert_eq!(chunk_ids(&hl_chunks), vec![(1, 344)]);
+  }
+
+  #[test]
+  fn hl_chunks_insert_lines() {
+    let mut chunks = HighlightChunks::default();
+    chunks.refresh(600, &[], line_ranges);
+
+    // two lines inserted in the first chunk; the second chunk is moved by Kakoune
+    chunks.apply_edit(&line_edit(10, 10, 12));
+    chunks.invalidate_lines(10..13);
+    let hl_chunks = chunks.refresh(602, &[], line_ranges);
+
+    assert_eq!(chunk_ids(&hl_chunks), vec![(0, 258)]);
+  }
+
+  #[test]
+  fn hl_chunks_dirty_only() {
+    let mut chunks = HighlightChunks::default();
+    chunks.refresh(600, &[], line_ranges);
+
+    // only the invalidated chunk is highlighted again
+    let mut highlighted = Vec::new();
+    chunks.invalidate_lines(400..401);
+    let hl_chunks = chunks.refresh(600, &[], |lines| {
+      highlighted.push(lines.to_vec());
+      line_ranges(lines)
+    });
+
+    assert_eq!(chunk_ids(&hl_chunks), vec![(1, 344)]);
+    assert_eq!(highlighted, vec![vec![256..600]]);
+  }
+
+  #[test]
+  fn hl_chunks_delete_lines() {
+    let mut chunks = HighlightChunks::default();
+    chunks.refresh(600, &[], line_ranges);
+
+    // lines spanning over both chunks removed; the second chunk is retired
+    chunks.apply_edit(&line_edit(200, 300, 200));
+    chunks.invalidate_lines(200..201);
+    let hl_chunks = chunks.refresh(500, &[], line_ranges);
+
+    assert_eq!(chunk_ids(&hl_chunks), vec![(1, 0), (0, 500)]);
+
+    // the retired chunk is reused when the buffer grows again
+    chunks.apply_edit(&line_edit(499, 499, 1099));
+    chunks.invalidate_lines(499..1100);
+    let hl_chunks = chunks.refresh(1100, &[], line_ranges);
+
+    assert_eq!(
+      chunk_ids(&hl_chunks),
+      vec![(0, 256), (1, 256), (2, 256), (3, 332)]
+    );
+  }
+
+  #[test]
+  fn hl_chunks_viewport() {
+    let mut chunks = HighlightChunks::default();
+    let mut highlighted = Vec::new();
+    let mut highlight = |lines: &[Range<usize>]| {
+      highlighted.push(lines.to_vec());
+      line_ranges(lines)
+    };
+
+    // only the chunk around the viewport is highlighted first
+    let hl_chunks = chunks.refresh(1100, slice::from_ref(&(600..650)), &mut highlight);
+    assert_eq!(chunk_ids(&hl_chunks), vec![(2, 256)]);
+    assert!(chunks.has_pending());
+
+    // the margin pulls the previous chunk in
+    let hl_chunks = chunks.refresh(1100, slice::from_ref(&(520..570)), &mut highlight);
+    assert_eq!(chunk_ids(&hl_chunks), vec![(1, 256)]);
+
+    let hl_chunks = chunks.refresh(1100, &[], &mut highlight);
+    assert_eq!(chunk_ids(&hl_chunks), vec![(0, 256), (3, 332)]);
+    assert!(!chunks.has_pending());
 
-    assert_eq!(events.len(), 70);
-
-    assert!(matches!(
-      events[..],
-      [
-        HighlightEvent::HighlightStart(Highlight(2)),
-        HighlightEvent::Source { start: 0, end: 2 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 2, end: 3 },
-        HighlightEvent::HighlightStart(Highlight(1)),
-        HighlightEvent::Source { start: 3, end: 6 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 6, end: 7 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(3)),
-        HighlightEvent::Source { start: 7, end: 8 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 8, end: 9 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 9, end: 10 },
-        HighlightEvent::HighlightStart(Highlight(5)),
-        HighlightEvent::Source { start: 10, end: 13 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 13, end: 14 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 14, end: 15 },
-        HighlightEvent::HighlightStart(Highlight(3)),
-        HighlightEvent::Source { start: 15, end: 16 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 16, end: 17 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 17, end: 18 },
-        HighlightEvent::HighlightStart(Highlight(6)),
-        HighlightEvent::Source { start: 18, end: 26 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 26, end: 27 },
-        HighlightEvent::HighlightStart(Highlight(2)),
-        HighlightEvent::Source { start: 27, end: 31 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 31, end: 32 },
-        HighlightEvent::HighlightStart(Highlight(5)),
-        HighlightEvent::Source { start: 32, end: 36 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 36, end: 37 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(5)),
-        HighlightEvent::Source { start: 37, end: 43 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 43, end: 44 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(5)),
-        HighlightEvent::Source { start: 44, end: 50 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 50, end: 51 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 51, end: 52 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 52, end: 53 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::Source { start: 53, end: 54 },
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 54, end: 55 },
-        HighlightEvent::HighlightEnd,
-        HighlightEvent::HighlightStart(Highlight(4)),
-        HighlightEvent::Source { start: 55, end: 56 },
-        HighlightEvent::HighlightEnd
+    // contiguous chunks are highlighted at once
+    assert_eq!(
+      highlighted,
+      vec![
+        vec![512..768],
+        vec![256..512],
+        vec![0..256],
+        vec![768..1100]
       ]
-    ));
+    );
   }
 }
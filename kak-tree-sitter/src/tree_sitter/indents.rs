@@ -0,0 +1,113 @@
+//! Indentation.
+//!
+//! The `indents.scm` query captures nodes with `@indent` and `@outdent`, the same way Helix does. A line is indented
+//! one level deeper for each `@indent` node it is in, if that node starts on a previous line; several `@indent` nodes
+//! starting on the same line only count once. A line starting with an `@outdent` node — typically a closing
+//! delimiter — is indented one level less.
+
+use std::collections::HashSet;
+
+use tree_sitter::{Point, Query, QueryCursor, Tree};
+
+use crate::{error::OhNo, tree_sitter::highlighting::satisfies_general_predicates};
+
+/// Indent configuration of a language.
+pub struct IndentConfig {
+  query: Query,
+  indent_capture: Option<u32>,
+  outdent_capture: Option<u32>,
+}
+
+impl IndentConfig {
+  /// Create an [`IndentConfig`] from an indents query.
+  pub fn new(lang: tree_sitter::Language, indents: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, indents)?;
+    let indent_capture = query.capture_index_for_name("indent");
+    let outdent_capture = query.capture_index_for_name("outdent");
+
+    Ok(Self {
+      query,
+      indent_capture,
+      outdent_capture,
+    })
+  }
+
+  /// Indentation level of a line (0-based).
+  pub fn indent_level(&self, tree: &Tree, source: &str, line: usize) -> usize {
+    let text = source.split('\n').nth(line).unwrap_or_default();
+    let first_col = text.len() - text.trim_start().len();
+    let is_blank = text.trim().is_empty();
+
+    let mut indent_rows = HashSet::new();
+    let mut outdent = false;
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(Point::new(line, 0)..Point::new(line + 1, 0));
+
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      for capture in qm.captures {
+        let start = capture.node.start_position();
+        let end = capture.node.end_position();
+
+        if Some(capture.index) == self.indent_capture {
+          let contains_line = line < end.row || (line == end.row && end.column > first_col);
+
+          if start.row < line && contains_line {
+            indent_rows.insert(start.row);
+          }
+        } else if Some(capture.index) == self.outdent_capture {
+          outdent |= !is_blank && start == Point::new(line, first_col);
+        }
+      }
+    }
+
+    indent_rows.len().saturating_sub(outdent as usize)
+  }
+}
+
+/// Indentation of a given level, with tabs if `indent_width` is `0`, or with as many spaces per level otherwise.
+pub fn indent_str(level: usize, indent_width: usize) -> String {
+  if indent_width == 0 {
+    "\t".repeat(level)
+  } else {
+    " ".repeat(level * indent_width)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::{indent_str, IndentConfig};
+
+  #[test]
+  fn indent_levels() {
+    let source = "fn f() {\n  let a = foo(\n    1,\n  );\n\n}";
+    let indents = r#"
+      [(block) (arguments)] @indent
+      ["}" ")"] @outdent
+    "#;
+    let config = IndentConfig::new(tree_sitter_rust::language(), indents).unwrap();
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let levels: Vec<_> = (0..6)
+      .map(|line| config.indent_level(&tree, source, line))
+      .collect();
+
+    assert_eq!(levels, vec![0, 1, 2, 1, 1, 0]);
+  }
+
+  #[test]
+  fn indent_with_tabs_or_spaces() {
+    assert_eq!(indent_str(2, 0), "\t\t");
+    assert_eq!(indent_str(2, 4), "        ");
+    assert_eq!(indent_str(0, 4), "");
+  }
+}
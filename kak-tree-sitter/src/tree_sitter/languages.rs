@@ -2,23 +2,48 @@
 //!
 //! Languages have different objects (grammars, queries, etc.) living at runtime and must be loaded beforehand.
 
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::Path, sync::Arc};
 
-use kak_tree_sitter_config::{Config, LanguagesConfig};
+use kak_tree_sitter_config::{Config, LanguageConfig, LanguagesConfig};
 use libloading::Symbol;
 use tree_sitter::Query;
-use tree_sitter_highlight::HighlightConfiguration;
 
-use crate::{error::OhNo, tree_sitter::queries::Queries};
+use crate::{
+  error::OhNo,
+  tree_sitter::{
+    context::ContextConfig, diagnostics::LintConfig, folds::FoldConfig,
+    highlighting::HighlightConfig, indents::IndentConfig, locals::LocalsConfig, queries::Queries,
+    rainbows::RainbowConfig, splitjoin::SplitJoinConfig, tags::TagsConfig,
+  },
+};
 
 pub struct Language {
   pub name: String,
-  pub hl_config: HighlightConfiguration,
-  pub hl_names: Vec<String>,
+  pub hl_config: HighlightConfig,
   // whether we should remove the default highlighter when highlighting a buffer with this language
   pub remove_default_highlighter: bool,
   // query to use for text objects, if supported by the language
   pub textobject_query: Option<Query>,
+  // rainbow delimiters, if supported by the language
+  pub rainbow_config: Option<RainbowConfig>,
+  // local scopes, definitions and references, if supported by the language; shared with the highlighter
+  pub locals_config: Option<Arc<LocalsConfig>>,
+  // indentation of lines, if supported by the language
+  pub indent_config: Option<IndentConfig>,
+  // foldable nodes, if supported by the language
+  pub fold_config: Option<FoldConfig>,
+  // symbol definitions, if supported by the language; shared with workspace indexing threads
+  pub tags_config: Option<Arc<TagsConfig>>,
+  // extensions of files written in the language
+  pub extensions: Vec<String>,
+  // templates nodes are wrapped with, by name
+  pub wrap_templates: HashMap<String, String>,
+  // nodes shown in breadcrumbs, if supported by the language
+  pub context_config: Option<ContextConfig>,
+  // nodes whose layout can be split and joined, if supported by the language
+  pub splitjoin_config: Option<SplitJoinConfig>,
+  // lints, if the language has some
+  pub lint_config: Option<LintConfig>,
 
   // NOTE: we need to keep that alive *probably*; better be safe than sorry
   ts_lang: tree_sitter::Language,
@@ -89,40 +114,13 @@ impl Languages {
           log::debug!("  queries directory: {}", queries_dir.display());
 
           let queries = Queries::load_from_dir(queries_dir);
-          let mut hl_config = match HighlightConfiguration::new(
-            ts_lang,
-            queries.highlights.as_deref().unwrap_or(""),
-            queries.injections.as_deref().unwrap_or(""),
-            queries.locals.as_deref().unwrap_or(""),
-          ) {
-            Ok(x) => x,
-            Err(err) => {
-              log::error!("failed to load highlighter for {lang_name}: {err}");
-              continue;
+          match Self::load_lang(config, lang_name, lang_config, ts_lang, ts_lib, &queries) {
+            Ok(lang) => {
+              langs.insert(lang_name.to_owned(), lang);
             }
-          };
-
-          let hl_names: Vec<_> = config.highlight.groups.iter().cloned().collect();
-          hl_config.configure(&hl_names);
-
-          let remove_default_highlighter = lang_config.remove_default_highlighter.into();
-
-          let textobject_query = queries
-            .text_objects
-            .as_deref()
-            .map(|q| Query::new(ts_lang, q).map(Some))
-            .unwrap_or_else(|| Ok(None))?;
-
-          let lang = Language {
-            name: lang_name.clone(),
-            hl_config,
-            hl_names,
-            remove_default_highlighter,
-            textobject_query,
-            ts_lang,
-            _ts_lib: ts_lib,
-          };
-          langs.insert(lang_name.to_owned(), lang);
+
+            Err(err) => log::error!("failed to load highlighter for {lang_name}: {err}"),
+          }
         }
       }
     }
@@ -130,6 +128,81 @@ impl Languages {
     Ok(Self { langs })
   }
 
+  /// Load a language from its grammar and queries.
+  ///
+  /// Only the highlighter is required; other queries failing to load are logged, and their features are not supported
+  /// by the language.
+  fn load_lang(
+    config: &Config,
+    lang_name: &str,
+    lang_config: &LanguageConfig,
+    ts_lang: tree_sitter::Language,
+    ts_lib: libloading::Library,
+    queries: &Queries,
+  ) -> Result<Language, OhNo> {
+    let textobject_query = load_optional(
+      lang_name,
+      "text-objects",
+      queries.text_objects.as_deref(),
+      |q| Query::new(ts_lang, q).map_err(OhNo::from),
+    );
+    let rainbow_config = load_optional(lang_name, "rainbows", queries.rainbows.as_deref(), |q| {
+      RainbowConfig::new(ts_lang, q)
+    });
+    let locals_config = load_optional(lang_name, "locals", queries.locals.as_deref(), |q| {
+      LocalsConfig::new(ts_lang, q).map(Arc::new)
+    });
+    let indent_config = load_optional(lang_name, "indents", queries.indents.as_deref(), |q| {
+      IndentConfig::new(ts_lang, q)
+    });
+    let fold_config = load_optional(lang_name, "folds", queries.folds.as_deref(), |q| {
+      FoldConfig::new(ts_lang, q)
+    });
+    let tags_config = load_optional(lang_name, "tags", queries.tags.as_deref(), |q| {
+      TagsConfig::new(ts_lang, q).map(Arc::new)
+    });
+    let context_config = load_optional(lang_name, "context", queries.context.as_deref(), |q| {
+      ContextConfig::new(ts_lang, q)
+    });
+    let splitjoin_config =
+      load_optional(lang_name, "splitjoin", queries.splitjoin.as_deref(), |q| {
+        SplitJoinConfig::new(ts_lang, q)
+      });
+    let lint_config = load_optional(lang_name, "lints", queries.lints.as_deref(), |q| {
+      LintConfig::new(ts_lang, q)
+    });
+
+    let mut hl_config = HighlightConfig::new(
+      ts_lang,
+      queries.highlights.as_deref().unwrap_or(""),
+      queries.injections.as_deref().unwrap_or(""),
+      |group| config.highlight.is_group_highlighted(group),
+      config.highlight.layered,
+    )?;
+    if let Some(ref locals_config) = locals_config {
+      hl_config = hl_config.with_locals(locals_config.clone());
+    }
+
+    Ok(Language {
+      name: lang_name.to_owned(),
+      hl_config,
+      remove_default_highlighter: lang_config.remove_default_highlighter.into(),
+      textobject_query,
+      rainbow_config,
+      locals_config,
+      indent_config,
+      fold_config,
+      tags_config,
+      extensions: lang_config.extensions.clone(),
+      wrap_templates: lang_config.wrap.clone(),
+      context_config,
+      splitjoin_config,
+      lint_config,
+      ts_lang,
+      _ts_lib: ts_lib,
+    })
+  }
+
   pub fn get(&self, lang: impl AsRef<str>) -> Result<&Language, OhNo> {
     let lang = lang.as_ref();
     self.langs.get(lang).ok_or_else(|| OhNo::UnknownLang {
@@ -141,3 +214,53 @@ impl Languages {
     self.langs.iter().map(|(name, lang)| (name.as_str(), lang))
   }
 }
+
+/// Load an optional query of a language.
+///
+/// Errors are logged, and the query is then ignored.
+fn load_optional<T>(
+  lang_name: &str,
+  kind: &str,
+  query: Option<&str>,
+  load: impl FnOnce(&str) -> Result<T, OhNo>,
+) -> Option<T> {
+  match load(query?) {
+    Ok(x) => Some(x),
+    Err(err) => {
+      log::error!("failed to load {kind} queries for {lang_name}: {err}");
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use kak_tree_sitter_config::Config;
+
+  use super::{Languages, Queries};
+
+  #[test]
+  fn bad_optional_query() {
+    let config = Config::load_default_config().unwrap();
+    let queries = Queries {
+      highlights: Some("(identifier) @variable".to_owned()),
+      locals: Some("(not_a_node) @local.scope".to_owned()),
+      ..Queries::default()
+    };
+    let ts_lib = libloading::os::unix::Library::this().into();
+
+    let lang = Languages::load_lang(
+      &config,
+      "rust",
+      &config.languages.language["rust"],
+      tree_sitter_rust::language(),
+      ts_lib,
+      &queries,
+    )
+    .unwrap();
+
+    // the language loads without locals
+    assert!(lang.locals_config.is_none());
+    assert_eq!(lang.hl_config.groups().collect::<Vec<_>>(), ["variable"]);
+  }
+}
@@ -0,0 +1,349 @@
+//! Local symbols.
+//!
+//! The `locals.scm` query captures scopes with `@local.scope`, definitions of symbols with `@local.definition` (and its
+//! sub-captures, such as `@local.definition.function`) and references to symbols with `@local.reference`. A reference
+//! resolves to the last definition of the same name preceding it in its scope, or in the enclosing scopes, unless a
+//! scope sets `(#set! local.scope-inherits false)`.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use tree_sitter::{Node, Point, Query, QueryCursor, Range, Tree};
+
+use crate::{error::OhNo, tree_sitter::highlighting::satisfies_general_predicates};
+
+/// Locals configuration of a language.
+pub struct LocalsConfig {
+  query: Query,
+  scope_capture: Option<u32>,
+  reference_capture: Option<u32>,
+
+  // whether each capture is a definition
+  definition_captures: Vec<bool>,
+
+  // whether scopes of a pattern inherit the definitions of their parent scope
+  scope_inherits: Vec<bool>,
+}
+
+/// A local symbol: its definition, if known, and its references.
+#[derive(Debug, Eq, PartialEq)]
+pub struct LocalSymbol {
+  pub definition: Option<Range>,
+  pub references: Vec<Range>,
+}
+
+impl LocalSymbol {
+  /// Ranges of the definition and the references, sorted by position.
+  pub fn ranges(&self) -> Vec<Range> {
+    let mut ranges: Vec<_> = self
+      .definition
+      .iter()
+      .chain(&self.references)
+      .copied()
+      .collect();
+    ranges.sort_by_key(|range| range.start_byte);
+    ranges
+  }
+}
+
+/// A definition of, or a reference to, a local symbol.
+#[derive(Debug)]
+pub struct Local<'a> {
+  pub range: Range,
+  pub name: &'a str,
+}
+
+/// Definitions and references of a tree, with references resolved to their definitions.
+#[derive(Debug, Default)]
+pub struct Locals<'a> {
+  pub definitions: Vec<Local<'a>>,
+
+  // scope of each definition
+  pub scopes: Vec<Range>,
+
+  // references, along with the index of the definition they resolve to, if any
+  pub references: Vec<(Local<'a>, Option<usize>)>,
+
+  // every scope walked, in order of appearance
+  pub walked_scopes: Vec<Range>,
+}
+
+/// Kind of a capture of the locals query.
+///
+/// Variants are ordered so that, for the same node, scopes come before definitions, and definitions before references.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum CaptureKind {
+  Scope { inherits: bool },
+  Definition,
+  Reference,
+}
+
+/// A scope being walked, with the definitions found in it so far.
+struct Frame<'a> {
+  range: Range,
+  inherits: bool,
+  definitions: HashMap<&'a str, usize>,
+}
+
+impl LocalsConfig {
+  /// Create a [`LocalsConfig`] from a locals query.
+  pub fn new(lang: tree_sitter::Language, locals: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, locals)?;
+    let scope_capture = query.capture_index_for_name("local.scope");
+    let reference_capture = query.capture_index_for_name("local.reference");
+    let definition_captures = query
+      .capture_names()
+      .iter()
+      .map(|name| name == "local.definition" || name.starts_with("local.definition."))
+      .collect();
+    let scope_inherits = (0..query.pattern_count())
+      .map(|pattern| {
+        !query.property_settings(pattern).iter().any(|prop| {
+          &*prop.key == "local.scope-inherits" && prop.value.as_deref() == Some("false")
+        })
+      })
+      .collect();
+
+    Ok(Self {
+      query,
+      scope_capture,
+      reference_capture,
+      definition_captures,
+      scope_inherits,
+    })
+  }
+
+  /// Find the local symbol defined or referenced at the given position.
+  ///
+  /// Only the part of the buffer before the position is walked to find the definition of the symbol, and then only
+  /// the scope of that definition to find its references.
+  ///
+  /// References that do not resolve to any definition are considered to refer to the same symbol if they have the
+  /// same name; this is typically the case of global symbols. The whole buffer is walked to find them.
+  pub fn symbol_at(&self, tree: &Tree, source: &str, pos: Point) -> Option<LocalSymbol> {
+    let root = tree.root_node();
+    let locals = self.walk(
+      root,
+      source,
+      Point::default()..Point::new(pos.row, pos.column + 1),
+    );
+
+    let contains = |local: &Local| local.range.start_point <= pos && pos < local.range.end_point;
+    let definition = match locals.definitions.iter().position(contains) {
+      Some(definition) => Some(definition),
+      None => {
+        locals
+          .references
+          .iter()
+          .find(|(local, _)| contains(local))?
+          .1
+      }
+    };
+    let (name, scope, definition) = match definition {
+      Some(def) => (
+        locals.definitions[def].name,
+        locals.scopes[def],
+        Some(locals.definitions[def].range),
+      ),
+      None => {
+        let (reference, _) = locals
+          .references
+          .iter()
+          .find(|(local, _)| contains(local))?;
+        (reference.name, root.range(), None)
+      }
+    };
+
+    let locals = self.walk(root, source, scope.start_point..scope.end_point);
+    let def_index = definition.and_then(|range| {
+      locals
+        .definitions
+        .iter()
+        .position(|local| local.range == range)
+    });
+    let references = locals
+      .references
+      .iter()
+      .filter(|(local, def)| local.name == name && *def == def_index)
+      .map(|(local, _)| local.range)
+      .collect();
+
+    Some(LocalSymbol {
+      definition,
+      references,
+    })
+  }
+
+  /// Collect the definitions and references under a node up to a position, resolving each reference to its
+  /// definition.
+  ///
+  /// Scopes are walked in a single pass, from the outermost to the innermost, keeping track of the definitions of each
+  /// enclosing scope. Nodes captured both as definitions and references are only kept as definitions. Definitions
+  /// always precede their references, so only the part of the buffer before `end` is walked.
+  pub fn resolve<'a>(&self, root: Node, source: &'a str, end: Point) -> Locals<'a> {
+    self.walk(root, source, Point::default()..end)
+  }
+
+  /// Walk the captures intersecting a range of points, resolving references to their definitions.
+  ///
+  /// References are resolved correctly if the range covers the whole scopes of their definitions.
+  fn walk<'a>(&self, root: Node, source: &'a str, points: std::ops::Range<Point>) -> Locals<'a> {
+    let mut captures = Vec::new();
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(points);
+    for qm in cursor.matches(&self.query, root, source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      for capture in qm.captures {
+        let kind = if Some(capture.index) == self.scope_capture {
+          CaptureKind::Scope {
+            inherits: self.scope_inherits[qm.pattern_index],
+          }
+        } else if self.definition_captures[capture.index as usize] {
+          CaptureKind::Definition
+        } else if Some(capture.index) == self.reference_capture {
+          CaptureKind::Reference
+        } else {
+          continue;
+        };
+
+        captures.push((capture.node.range(), kind));
+      }
+    }
+
+    captures.sort_by_key(|(range, kind)| (range.start_byte, Reverse(range.end_byte), *kind));
+    captures.dedup();
+
+    let mut locals = Locals::default();
+    let mut scopes = vec![Frame {
+      range: root.range(),
+      inherits: false,
+      definitions: HashMap::new(),
+    }];
+
+    for (range, kind) in captures {
+      while scopes.len() > 1
+        && scopes
+          .last()
+          .is_some_and(|scope| scope.range.end_byte <= range.start_byte)
+      {
+        scopes.pop();
+      }
+
+      if let CaptureKind::Scope { inherits } = kind {
+        locals.walked_scopes.push(range);
+        scopes.push(Frame {
+          range,
+          inherits,
+          definitions: HashMap::new(),
+        });
+        continue;
+      }
+
+      let Some(name) = source.get(range.start_byte..range.end_byte) else {
+        continue;
+      };
+
+      if kind == CaptureKind::Definition {
+        if let Some(scope) = scopes.last_mut() {
+          scope.definitions.insert(name, locals.definitions.len());
+          locals.scopes.push(scope.range);
+        }
+
+        locals.definitions.push(Local { range, name });
+        continue;
+      }
+
+      if locals
+        .definitions
+        .last()
+        .is_some_and(|def| def.range == range)
+      {
+        continue;
+      }
+
+      let mut definition = None;
+      for scope in scopes.iter().rev() {
+        if let Some(&def) = scope.definitions.get(name) {
+          definition = Some(def);
+          break;
+        }
+
+        if !scope.inherits {
+          break;
+        }
+      }
+
+      locals.references.push((Local { range, name }, definition));
+    }
+
+    locals
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Point, Tree};
+
+  use super::LocalsConfig;
+
+  const LOCALS: &str = r#"
+    [(function_item) (block)] @local.scope
+    (parameter pattern: (identifier) @local.definition)
+    (let_declaration pattern: (identifier) @local.definition)
+    (identifier) @local.reference
+  "#;
+
+  fn rust_tree(source: &str) -> Tree {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    parser.parse(source, None).unwrap()
+  }
+
+  /// (line, column) of the start of each range of the symbol at the given position.
+  fn symbol_at(source: &str, pos: Point) -> Vec<(usize, usize)> {
+    let config = LocalsConfig::new(tree_sitter_rust::language(), LOCALS).unwrap();
+    let symbol = config.symbol_at(&rust_tree(source), source, pos).unwrap();
+
+    symbol
+      .ranges()
+      .iter()
+      .map(|range| (range.start_point.row, range.start_point.column))
+      .collect()
+  }
+
+  #[test]
+  fn locals_scopes() {
+    let source = "fn f(a: i32) {\n  let b = a;\n  { let a = b; a; }\n  a\n}";
+
+    // the parameter, and the references that are not shadowed
+    assert_eq!(
+      symbol_at(source, Point::new(1, 10)),
+      vec![(0, 5), (1, 10), (3, 2)]
+    );
+
+    // the shadowing definition and its reference
+    assert_eq!(symbol_at(source, Point::new(2, 15)), vec![(2, 8), (2, 15)]);
+
+    // a definition is found from the definition itself as well
+    assert_eq!(symbol_at(source, Point::new(1, 6)), vec![(1, 6), (2, 12)]);
+  }
+
+  #[test]
+  fn locals_other_scopes() {
+    let source = "fn f() { let a = 1; a }\nfn g() { let a = 2; a }";
+
+    // definitions of the same name in other scopes are left out
+    assert_eq!(symbol_at(source, Point::new(1, 20)), vec![(1, 13), (1, 20)]);
+    assert_eq!(symbol_at(source, Point::new(0, 13)), vec![(0, 13), (0, 20)]);
+  }
+
+  #[test]
+  fn locals_unresolved() {
+    let source = "fn f() { g(); g(); }";
+
+    assert_eq!(symbol_at(source, Point::new(0, 14)), vec![(0, 9), (0, 14)]);
+  }
+}
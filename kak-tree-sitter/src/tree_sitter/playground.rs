@@ -0,0 +1,287 @@
+//! Query playground.
+//!
+//! Arbitrary queries — typed in Kakoune — are run against the tree of a buffer, so that their captures can be
+//! highlighted while writing them, or selected. Queries that do not compile are reported with the position of the
+//! error.
+
+use std::collections::HashMap;
+
+use tree_sitter::{Query, QueryCursor, QueryError, QueryErrorKind, Range, Tree};
+
+use crate::{
+  error::OhNo, kakoune::selection::Sel, tree_sitter::highlighting::satisfies_general_predicates,
+};
+
+/// Maximum number of compiled queries kept in a [`QueryCache`].
+const MAX_CACHED_QUERIES: usize = 32;
+
+/// Compiled ad-hoc queries, keyed by their language and source.
+///
+/// Queries must be dropped before the grammars they are compiled for are unloaded.
+#[derive(Default)]
+pub struct QueryCache {
+  queries: HashMap<(String, String), Query>,
+}
+
+impl QueryCache {
+  /// Get a compiled query, compiling it if it is not cached yet.
+  pub fn get_or_compile(
+    &mut self,
+    lang_name: &str,
+    lang: tree_sitter::Language,
+    query: &str,
+  ) -> Result<&Query, QueryError> {
+    let key = (lang_name.to_owned(), query.to_owned());
+
+    if !self.queries.contains_key(&key) {
+      let compiled = Query::new(lang, query)?;
+
+      if self.queries.len() >= MAX_CACHED_QUERIES {
+        self.queries.clear();
+      }
+
+      self.queries.insert(key.clone(), compiled);
+    }
+
+    Ok(&self.queries[&key])
+  }
+}
+
+/// A node captured by a query.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryCapture {
+  /// Name of the capture, without the leading `@`.
+  pub name: String,
+  pub range: Range,
+}
+
+/// Run a query against a tree, and return its captures sorted by position.
+pub fn captures(
+  lang: tree_sitter::Language,
+  tree: &Tree,
+  source: &str,
+  query: &str,
+) -> Result<Vec<QueryCapture>, QueryError> {
+  let query = Query::new(lang, query)?;
+  let mut captures = Vec::new();
+
+  let mut cursor = QueryCursor::new();
+  for qm in cursor.matches(&query, tree.root_node(), source.as_bytes()) {
+    if !satisfies_general_predicates(&query, &qm, source) {
+      continue;
+    }
+
+    for capture in qm.captures {
+      captures.push(QueryCapture {
+        name: query.capture_names()[capture.index as usize].clone(),
+        range: capture.node.range(),
+      });
+    }
+  }
+
+  captures.sort_by_key(|capture| (capture.range.start_byte, capture.range.end_byte));
+  captures.dedup();
+  Ok(captures)
+}
+
+/// Select the nodes captured with a given name that are inside selections.
+///
+/// Selections without any such node are dropped; if no selection has any, they are all kept as-is.
+pub fn select(
+  query: &Query,
+  capture: &str,
+  tree: &Tree,
+  source: &str,
+  selections: &[Sel],
+) -> Result<Vec<Sel>, OhNo> {
+  let capture_index =
+    query
+      .capture_index_for_name(capture)
+      .ok_or_else(|| OhNo::UnknownCapture {
+        capture: capture.to_owned(),
+      })?;
+  let mut sels = Vec::new();
+
+  let mut cursor = QueryCursor::new();
+  for qm in cursor.matches(query, tree.root_node(), source.as_bytes()) {
+    if !satisfies_general_predicates(query, &qm, source) {
+      continue;
+    }
+
+    for capture in qm.captures {
+      let node = capture.node;
+
+      if capture.index != capture_index || node.start_byte() == node.end_byte() {
+        continue;
+      }
+
+      if selections.iter().any(|sel| sel.selects(&node)) {
+        sels.push(Sel::from_ts_range(&node.range(), source));
+      }
+    }
+  }
+
+  if sels.is_empty() {
+    return Ok(selections.to_vec());
+  }
+
+  sels.sort_by_key(|sel| (sel.anchor, sel.cursor));
+  sels.dedup();
+  Ok(sels)
+}
+
+/// Human-readable description of a query error, with its position as `<line>:<column>` and its byte offset.
+pub fn error_message(err: &QueryError) -> String {
+  let kind = match err.kind {
+    QueryErrorKind::Syntax => "invalid syntax",
+    QueryErrorKind::NodeType => "invalid node type",
+    QueryErrorKind::Field => "invalid field",
+    QueryErrorKind::Capture => "invalid capture",
+    QueryErrorKind::Predicate => "invalid predicate",
+    QueryErrorKind::Structure => "impossible pattern",
+    QueryErrorKind::Language => "invalid language",
+  };
+  let message = err.message.split_whitespace().next().unwrap_or_default();
+
+  format!(
+    "query error at {line}:{col} (offset {offset}): {kind} {message}",
+    line = err.row + 1,
+    col = err.column + 1,
+    offset = err.offset,
+  )
+  .trim_end()
+  .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::{captures, error_message, select, QueryCache};
+  use crate::kakoune::selection::Sel;
+
+  #[test]
+  fn query_captures() {
+    let source = "fn f() { g(1); }";
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let query = r#"
+      (function_item name: (identifier) @name) @function
+      (call_expression function: (identifier) @name (#eq? @name "g"))
+    "#;
+    let found: Vec<_> = captures(tree_sitter_rust::language(), &tree, source, query)
+      .unwrap()
+      .into_iter()
+      .map(|capture| {
+        (
+          capture.name,
+          capture.range.start_byte,
+          capture.range.end_byte,
+        )
+      })
+      .collect();
+
+    assert_eq!(
+      found,
+      vec![
+        ("function".to_owned(), 0, 16),
+        ("name".to_owned(), 3, 4),
+        ("name".to_owned(), 9, 10),
+      ]
+    );
+  }
+
+  #[test]
+  fn select_captures() {
+    let source = "fn f() {\n  log::info!(\"a\");\n  g(\"b\");\n}\nfn h() { log::warn!(\"c\"); }";
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let query = r#"
+      (macro_invocation
+        macro: (scoped_identifier path: (identifier) @path (#eq? @path "log"))
+        (token_tree (string_literal) @string))
+    "#;
+    let mut cache = QueryCache::default();
+    let query = cache
+      .get_or_compile("rust", tree_sitter_rust::language(), query)
+      .unwrap();
+
+    // only in the first function
+    let sels = [Sel::parse_kak_str("1.1,4.1").unwrap()];
+    assert_eq!(
+      select(query, "string", &tree, source, &sels).unwrap(),
+      vec![Sel::parse_kak_str("2.14,2.16").unwrap()]
+    );
+
+    // nothing in the selection
+    let sels = [Sel::parse_kak_str("3.3,3.9").unwrap()];
+    assert_eq!(
+      select(query, "string", &tree, source, &sels).unwrap(),
+      sels.to_vec()
+    );
+
+    assert!(select(query, "nope", &tree, source, &sels).is_err());
+  }
+
+  #[test]
+  fn query_errors() {
+    let source = "fn f() {}";
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let err = captures(
+      tree_sitter_rust::language(),
+      &tree,
+      source,
+      "(function_item)\n(not_a_node) @x",
+    )
+    .unwrap_err();
+
+    assert_eq!(
+      error_message(&err),
+      "query error at 2:2 (offset 17): invalid node type not_a_node"
+    );
+  }
+
+  #[test]
+  fn query_cache() {
+    let mut cache = QueryCache::default();
+    let lang = tree_sitter_rust::language();
+
+    // queries are told apart by their source, and by the language they are compiled for
+    let names = |query: &tree_sitter::Query| query.capture_names().to_vec();
+    assert_eq!(
+      names(
+        cache
+          .get_or_compile("rust", lang, "(identifier) @a")
+          .unwrap()
+      ),
+      vec!["a"]
+    );
+    assert_eq!(
+      names(
+        cache
+          .get_or_compile("rust", lang, "(identifier) @b")
+          .unwrap()
+      ),
+      vec!["b"]
+    );
+    assert_eq!(
+      names(
+        cache
+          .get_or_compile("rust", lang, "(identifier) @a")
+          .unwrap()
+      ),
+      vec!["a"]
+    );
+    assert!(cache
+      .get_or_compile("other", lang, "(identifier) @a")
+      .is_ok());
+    assert_eq!(cache.queries.len(), 3);
+  }
+}
@@ -2,12 +2,19 @@
 
 use std::{fs, path::Path};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Queries {
   pub highlights: Option<String>,
   pub injections: Option<String>,
+  pub indents: Option<String>,
+  pub folds: Option<String>,
+  pub tags: Option<String>,
+  pub context: Option<String>,
   pub locals: Option<String>,
   pub text_objects: Option<String>,
+  pub lints: Option<String>,
+  pub rainbows: Option<String>,
+  pub splitjoin: Option<String>,
 }
 
 impl Queries {
@@ -16,14 +23,28 @@ impl Queries {
 
     let highlights = fs::read_to_string(dir.join("highlights.scm")).ok();
     let injections = fs::read_to_string(dir.join("injections.scm")).ok();
+    let indents = fs::read_to_string(dir.join("indents.scm")).ok();
+    let folds = fs::read_to_string(dir.join("folds.scm")).ok();
+    let tags = fs::read_to_string(dir.join("tags.scm")).ok();
+    let context = fs::read_to_string(dir.join("context.scm")).ok();
     let locals = fs::read_to_string(dir.join("locals.scm")).ok();
     let text_objects = fs::read_to_string(dir.join("textobjects.scm")).ok();
+    let lints = fs::read_to_string(dir.join("lints.scm")).ok();
+    let rainbows = fs::read_to_string(dir.join("rainbows.scm")).ok();
+    let splitjoin = fs::read_to_string(dir.join("splitjoin.scm")).ok();
 
     Queries {
       highlights,
       injections,
+      indents,
+      folds,
+      tags,
+      context,
       locals,
       text_objects,
+      lints,
+      rainbows,
+      splitjoin,
     }
   }
 }
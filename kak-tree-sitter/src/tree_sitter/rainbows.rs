@@ -0,0 +1,159 @@
+//! Rainbow delimiters.
+//!
+//! The `rainbows.scm` query captures scopes with `@rainbow.scope`, and delimiters with `@rainbow.bracket`. Brackets are
+//! highlighted with a face depending on how deeply their scope is nested in other scopes.
+
+use std::ops::Range;
+
+use tree_sitter::{Node, Query, QueryCursor, Tree};
+
+use crate::{
+  error::OhNo,
+  tree_sitter::highlighting::{satisfies_general_predicates, KakHighlightRange, Window},
+};
+
+/// Number of rainbow faces; nesting levels deeper than that cycle through them again.
+pub const RAINBOW_FACES: usize = 6;
+
+/// Rainbow configuration of a language.
+pub struct RainbowConfig {
+  query: Query,
+  scope_capture: Option<u32>,
+  bracket_capture: Option<u32>,
+
+  // whether brackets of a pattern can be any descendant of their scope, instead of direct children only; this is set
+  // with (#set! rainbow.include-children)
+  include_children: Vec<bool>,
+}
+
+impl RainbowConfig {
+  /// Create a [`RainbowConfig`] from a rainbows query.
+  pub fn new(lang: tree_sitter::Language, rainbows: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, rainbows)?;
+    let scope_capture = query.capture_index_for_name("rainbow.scope");
+    let bracket_capture = query.capture_index_for_name("rainbow.bracket");
+    let include_children = (0..query.pattern_count())
+      .map(|pattern| {
+        query
+          .property_settings(pattern)
+          .iter()
+          .any(|prop| &*prop.key == "rainbow.include-children")
+      })
+      .collect();
+
+    Ok(Self {
+      query,
+      scope_capture,
+      bracket_capture,
+      include_children,
+    })
+  }
+
+  /// Highlight the brackets of chunks of lines of a buffer, given its already parsed tree.
+  ///
+  /// `chunks` are contiguous, _0-based_ line ranges; see [`HighlightConfig::highlight`].
+  ///
+  /// [`HighlightConfig::highlight`]: crate::tree_sitter::highlighting::HighlightConfig::highlight
+  pub fn rainbows(
+    &self,
+    tree: &Tree,
+    source: &str,
+    chunks: &[Range<usize>],
+  ) -> Vec<Vec<KakHighlightRange>> {
+    let window = Window::new(source, chunks);
+    let bytes = window.bytes();
+    let mut scopes: Vec<(Node, bool)> = Vec::new();
+    let mut brackets: Vec<(usize, usize, usize)> = Vec::new();
+
+    // scopes enclosing the window are captured as well, so nesting levels are the same as for the whole buffer
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(window.points());
+    for (qm, index) in cursor.captures(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      let capture = qm.captures[index];
+      let node = capture.node;
+
+      // leave the scopes ending before the captured node
+      while scopes
+        .last()
+        .is_some_and(|(scope, _)| scope.end_byte() <= node.start_byte())
+      {
+        scopes.pop();
+      }
+
+      if Some(capture.index) == self.scope_capture {
+        // the same node might be captured as a scope by several patterns
+        if scopes.last().map(|(scope, _)| *scope) != Some(node) {
+          scopes.push((node, self.include_children[qm.pattern_index]));
+        }
+      } else if Some(capture.index) == self.bracket_capture {
+        let Some((scope, include_children)) = scopes.last() else {
+          continue;
+        };
+
+        let (start, end) = (node.start_byte(), node.end_byte());
+        let is_in_scope = *include_children || node.parent() == Some(*scope);
+        let is_new = brackets
+          .last()
+          .map_or(true, |&(s, e, _)| (s, e) != (start, end));
+
+        if start < end && bytes.contains(&start) && is_in_scope && is_new {
+          brackets.push((start, end, scopes.len() - 1));
+        }
+      }
+    }
+
+    window.kak_ranges(
+      source,
+      brackets
+        .into_iter()
+        .map(|(start, end, level)| (start..end, format!("ts_rainbow_{}", level % RAINBOW_FACES))),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::slice;
+
+  use tree_sitter::Parser;
+
+  use super::RainbowConfig;
+  use crate::tree_sitter::highlighting::KakHighlightRange;
+
+  #[test]
+  fn rainbow_brackets() {
+    let source = "fn f() { g(h(1)); }";
+    let rainbows = r#"
+      [(parameters) (arguments) (block)] @rainbow.scope
+      ["(" ")" "{" "}"] @rainbow.bracket
+    "#;
+    let config = RainbowConfig::new(tree_sitter_rust::language(), rainbows).unwrap();
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let ranges: Vec<_> = config.rainbows(&tree, source, slice::from_ref(&(0..1)))[0]
+      .iter()
+      .map(KakHighlightRange::to_kak_range_str)
+      .collect();
+
+    assert_eq!(
+      ranges,
+      vec![
+        "1.5,1.5|ts_rainbow_0",
+        "1.6,1.6|ts_rainbow_0",
+        "1.8,1.8|ts_rainbow_0",
+        "1.11,1.11|ts_rainbow_1",
+        "1.13,1.13|ts_rainbow_2",
+        "1.15,1.15|ts_rainbow_2",
+        "1.16,1.16|ts_rainbow_1",
+        "1.19,1.19|ts_rainbow_0",
+      ]
+    );
+  }
+}
@@ -0,0 +1,183 @@
+//! Structural search and replace.
+//!
+//! The outermost node captured by each match of a query — typically the whole pattern — is replaced with a template,
+//! in which `@<capture>` is substituted with the text of that capture, and `@@` with a literal `@`. The text of a
+//! capture goes from the start of its first node to the end of its last one, so that quantified captures keep their
+//! separators. Overlapping matches are only replaced once, by their outermost one.
+
+use std::cmp::Reverse;
+
+use tree_sitter::{Query, QueryCursor, Tree};
+
+use crate::tree_sitter::{edits::Edit, highlighting::satisfies_general_predicates};
+
+/// Compute the edits replacing every match of a query with a template, sorted by position.
+pub fn replacements(query: &Query, tree: &Tree, source: &str, template: &str) -> Vec<Edit> {
+  // (start, end, number of captures, range, text)
+  let mut replacements = Vec::new();
+
+  let mut cursor = QueryCursor::new();
+  for qm in cursor.matches(query, tree.root_node(), source.as_bytes()) {
+    if !satisfies_general_predicates(query, &qm, source) {
+      continue;
+    }
+
+    let Some(outermost) = qm
+      .captures
+      .iter()
+      .map(|capture| capture.node)
+      .max_by_key(|node| {
+        (
+          node.end_byte() - node.start_byte(),
+          Reverse(node.start_byte()),
+        )
+      })
+    else {
+      continue;
+    };
+
+    if outermost.start_byte() == outermost.end_byte() {
+      continue;
+    }
+
+    let capture_text = |name: &str| {
+      let index = query.capture_index_for_name(name)?;
+      let nodes = qm.captures.iter().filter(|capture| capture.index == index);
+      let start = nodes.clone().map(|capture| capture.node.start_byte()).min();
+      let end = nodes.map(|capture| capture.node.end_byte()).max();
+
+      match start.zip(end) {
+        Some((start, end)) => source.get(start..end),
+        None => Some(""),
+      }
+    };
+
+    replacements.push((
+      outermost.start_byte(),
+      outermost.end_byte(),
+      qm.captures.len(),
+      outermost.range(),
+      expand(template, capture_text),
+    ));
+  }
+
+  // several matches might replace the same node; the one with the most captures is kept
+  replacements.sort_by_key(|&(start, end, captures, ..)| (start, Reverse(end), Reverse(captures)));
+
+  let mut last_end = 0;
+  replacements
+    .into_iter()
+    .filter(|&(start, end, ..)| {
+      let overlaps = start < last_end;
+      last_end = last_end.max(end);
+      !overlaps
+    })
+    .map(|(_, _, _, range, text)| Edit { range, text })
+    .collect()
+}
+
+/// Expand a template, substituting `@<capture>` with the text of the capture, if it exists.
+///
+/// Capture names are read greedily, and then shortened until they name a capture, so that punctuation can follow
+/// them — e.g. `@callee.` is read as `@callee` followed by `.` if there is no `callee.` capture.
+pub fn expand<'a>(template: &str, capture_text: impl Fn(&str) -> Option<&'a str>) -> String {
+  let is_name_char = |c: char| c.is_alphanumeric() || c == '_' || c == '.' || c == '-';
+  let mut expanded = String::new();
+  let mut rest = template;
+
+  while let Some(at) = rest.find('@') {
+    expanded.push_str(&rest[..at]);
+    rest = &rest[at + 1..];
+
+    if let Some(after) = rest.strip_prefix('@') {
+      expanded.push('@');
+      rest = after;
+      continue;
+    }
+
+    // only try lengths ending on char boundaries, longest first
+    let name_len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+    let substitution = rest[..name_len]
+      .char_indices()
+      .map(|(i, c)| i + c.len_utf8())
+      .rev()
+      .find_map(|len| Some((len, capture_text(&rest[..len])?)));
+
+    match substitution {
+      Some((len, text)) => {
+        expanded.push_str(text);
+        rest = &rest[len..];
+      }
+
+      None => expanded.push('@'),
+    }
+  }
+
+  expanded.push_str(rest);
+  expanded
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Query};
+
+  use super::{expand, replacements};
+  use crate::kakoune::selection::Sel;
+
+  #[test]
+  fn expand_templates() {
+    let capture_text = |name: &str| match name {
+      "callee" => Some("f"),
+      "args" => Some("a, b"),
+      "café" => Some("c"),
+      _ => None,
+    };
+
+    assert_eq!(
+      expand("@callee(@args, None)", capture_text),
+      "f(a, b, None)"
+    );
+    assert_eq!(
+      expand("@callee.x @@args @nope", capture_text),
+      "f.x @args @nope"
+    );
+    assert_eq!(expand("@café.é @été", capture_text), "c.é @été");
+  }
+
+  #[test]
+  fn replace_matches() {
+    let source = "fn main() {\n  foo(1, 2);\n  bar(foo(3));\n}";
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let query = Query::new(
+      tree_sitter_rust::language(),
+      r#"
+        (call_expression
+          function: (identifier) @callee
+          arguments: (arguments ((_) @args ("," (_) @args)*))) @call
+      "#,
+    )
+    .unwrap();
+
+    // the inner call of the second line overlaps the outer one, and is not replaced
+    let edits: Vec<_> = replacements(&query, &tree, source, "@callee(@args, None)")
+      .into_iter()
+      .map(|edit| (edit.sel(source), edit.text))
+      .collect();
+    assert_eq!(
+      edits,
+      vec![
+        (
+          Sel::parse_kak_str("2.3,2.11").unwrap(),
+          "foo(1, 2, None)".to_owned()
+        ),
+        (
+          Sel::parse_kak_str("3.3,3.13").unwrap(),
+          "bar(foo(3), None)".to_owned()
+        ),
+      ]
+    );
+  }
+}
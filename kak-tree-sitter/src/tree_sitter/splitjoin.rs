@@ -0,0 +1,271 @@
+//! Splitting and joining delimited nodes.
+//!
+//! The `splitjoin.scm` query captures the nodes whose layout can be toggled with `@splitjoin` — argument lists,
+//! arrays, object literals, import lists, etc. Such nodes must start and end with anonymous delimiters; the named
+//! nodes between them are the items, and the anonymous nodes following items are their separators.
+//!
+//! A node on a single line is split, with one item per line indented one level deeper than the line the node starts
+//! on, and its closing delimiter on its own line at the indentation of that line. A node on several lines is joined on
+//! a single line. Patterns can set the following properties:
+//!
+//! - `splitjoin.trailing-separator`: add a separator after the last item when splitting.
+//! - `splitjoin.padding`: surround the items with spaces when joining — e.g. `{ a, b }`.
+
+use tree_sitter::{Node, Point, Query, QueryCursor, Tree};
+
+use crate::{
+  error::OhNo,
+  tree_sitter::{edits::Edit, highlighting::satisfies_general_predicates, indents},
+};
+
+/// Split/join configuration of a language.
+pub struct SplitJoinConfig {
+  query: Query,
+  splitjoin_capture: Option<u32>,
+  // properties, by pattern
+  trailing_separator: Vec<bool>,
+  padding: Vec<bool>,
+}
+
+impl SplitJoinConfig {
+  /// Create a [`SplitJoinConfig`] from a splitjoin query.
+  pub fn new(lang: tree_sitter::Language, splitjoin: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, splitjoin)?;
+    let splitjoin_capture = query.capture_index_for_name("splitjoin");
+    let has_property = |key: &str| {
+      (0..query.pattern_count())
+        .map(|pattern| {
+          query
+            .property_settings(pattern)
+            .iter()
+            .any(|prop| &*prop.key == key)
+        })
+        .collect()
+    };
+    let trailing_separator = has_property("splitjoin.trailing-separator");
+    let padding = has_property("splitjoin.padding");
+
+    Ok(Self {
+      query,
+      splitjoin_capture,
+      trailing_separator,
+      padding,
+    })
+  }
+
+  /// Toggle the layout of the innermost node around a position.
+  ///
+  /// Nodes containing comments, or whose items span several lines, are not joined.
+  pub fn toggle(&self, tree: &Tree, source: &str, pos: Point, indent_width: usize) -> Option<Edit> {
+    let (node, pattern) = self.find(tree, source, pos)?;
+    let items = items(node, source)?;
+    let open = node.child(0)?.utf8_text(source.as_bytes()).ok()?;
+    let close = node
+      .child(node.child_count() - 1)?
+      .utf8_text(source.as_bytes())
+      .ok()?;
+
+    let text = if node.start_position().row == node.end_position().row {
+      let indent = line_indent(source, node.start_byte());
+      let item_indent = format!("{indent}{}", indents::indent_str(1, indent_width));
+      // separator to add after the last item, if it has none
+      let trailing_separator = items
+        .iter()
+        .find_map(|item| item.separator)
+        .filter(|_| self.trailing_separator[pattern])
+        .unwrap_or_default();
+
+      let mut text = format!("{open}\n");
+      for (i, item) in items.iter().enumerate() {
+        let separator = match item.separator {
+          Some(separator) => separator,
+          None if i == items.len() - 1 => trailing_separator,
+          None => "",
+        };
+        text.push_str(&format!("{item_indent}{}{separator}\n", item.text));
+      }
+      text.push_str(&format!("{indent}{close}"));
+      text
+    } else {
+      if items
+        .iter()
+        .any(|item| item.is_comment || item.text.contains('\n'))
+      {
+        return None;
+      }
+
+      let joined = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+          // a single item keeps its separator, since it might be meaningful — e.g. `(a,)` in Rust
+          let is_trailing = i == items.len() - 1 && items.len() > 1;
+          match item.separator {
+            Some(separator) if !is_trailing => format!("{}{separator}", item.text),
+            _ => item.text.to_owned(),
+          }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+      let padding = if self.padding[pattern] { " " } else { "" };
+
+      format!("{open}{padding}{joined}{padding}{close}")
+    };
+
+    Some(Edit {
+      range: node.range(),
+      text,
+    })
+  }
+
+  /// Innermost node captured around a position, along with the index of the pattern capturing it.
+  fn find<'a>(&self, tree: &'a Tree, source: &str, pos: Point) -> Option<(Node<'a>, usize)> {
+    let mut found: Option<(Node, usize)> = None;
+
+    let mut cursor = QueryCursor::new();
+    cursor.set_point_range(pos..Point::new(pos.row, pos.column + 1));
+
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      for capture in qm.captures {
+        let node = capture.node;
+
+        if Some(capture.index) != self.splitjoin_capture
+          || pos < node.start_position()
+          || node.end_position() <= pos
+        {
+          continue;
+        }
+
+        let is_inner = found.map_or(true, |(inner, _)| {
+          node.end_byte() - node.start_byte() < inner.end_byte() - inner.start_byte()
+        });
+
+        if is_inner {
+          found = Some((node, qm.pattern_index));
+        }
+      }
+    }
+
+    found
+  }
+}
+
+/// An item of a node, with the separator following it.
+struct Item<'a> {
+  text: &'a str,
+  separator: Option<&'a str>,
+  is_comment: bool,
+}
+
+/// Items of a delimited node; `None` if the node is not delimited, has no items, or has separators not following an
+/// item.
+fn items<'a>(node: Node, source: &'a str) -> Option<Vec<Item<'a>>> {
+  let count = node.child_count();
+  if count < 3 || node.child(0)?.is_named() || node.child(count - 1)?.is_named() {
+    return None;
+  }
+
+  let mut items: Vec<Item> = Vec::new();
+  for i in 1..count - 1 {
+    let child = node.child(i)?;
+    let text = source.get(child.byte_range())?.trim();
+
+    if child.is_named() || child.is_extra() {
+      items.push(Item {
+        text,
+        separator: None,
+        is_comment: child.is_extra(),
+      });
+    } else {
+      let item = items.last_mut().filter(|item| item.separator.is_none())?;
+      item.separator = Some(text);
+    }
+  }
+
+  (!items.is_empty()).then_some(items)
+}
+
+/// Indentation of the line containing a byte offset.
+fn line_indent(source: &str, offset: usize) -> &str {
+  let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+  let line = &source[line_start..];
+  let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+
+  &line[..indent_len]
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Point};
+
+  use super::SplitJoinConfig;
+
+  /// Toggle the node at a position, and return the edited source.
+  fn toggled(source: &str, pos: Point) -> Option<String> {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    let config = SplitJoinConfig::new(
+      tree_sitter_rust::language(),
+      r#"
+        ((arguments) @splitjoin (#set! splitjoin.trailing-separator))
+        ((use_list) @splitjoin (#set! splitjoin.padding))
+      "#,
+    )
+    .unwrap();
+
+    let edit = config.toggle(&tree, source, pos, 2)?;
+    Some(format!(
+      "{}{}{}",
+      &source[..edit.range.start_byte],
+      edit.text,
+      &source[edit.range.end_byte..]
+    ))
+  }
+
+  #[test]
+  fn split_arguments() {
+    let source = "fn f() {\n  foo(a, bar(b, c));\n}";
+
+    assert_eq!(
+      toggled(source, Point::new(1, 6)).as_deref(),
+      Some("fn f() {\n  foo(\n    a,\n    bar(b, c),\n  );\n}")
+    );
+    assert_eq!(
+      toggled(source, Point::new(1, 13)).as_deref(),
+      Some("fn f() {\n  foo(a, bar(\n    b,\n    c,\n  ));\n}")
+    );
+    assert_eq!(toggled(source, Point::new(0, 1)), None);
+  }
+
+  #[test]
+  fn join_arguments() {
+    let source = "fn f() {\n  foo(\n    a,\n    (b,),\n  );\n}";
+
+    assert_eq!(
+      toggled(source, Point::new(2, 4)).as_deref(),
+      Some("fn f() {\n  foo(a, (b,));\n}")
+    );
+
+    // comments are kept on their own line
+    let source = "fn f() {\n  foo(\n    a, // a\n    b,\n  );\n}";
+    assert_eq!(toggled(source, Point::new(2, 4)), None);
+  }
+
+  #[test]
+  fn split_join_imports() {
+    let source = "use std::{fs, io};";
+    let split = "use std::{\n  fs,\n  io\n};";
+
+    assert_eq!(toggled(source, Point::new(0, 11)).as_deref(), Some(split));
+    assert_eq!(
+      toggled(split, Point::new(1, 2)).as_deref(),
+      Some("use std::{ fs, io };")
+    );
+  }
+}
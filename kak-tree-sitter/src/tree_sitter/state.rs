@@ -1,21 +1,39 @@
 //! Tree-sitter state (i.e. highlighting, tree walking, etc.)
 
-use std::collections::{hash_map::Entry, HashMap};
+use std::{
+  collections::{hash_map::Entry, HashMap},
+  ops::Range,
+  sync::Arc,
+};
 
 use mio::Token;
-use tree_sitter::{Node, Parser, QueryCursor};
+use tree_sitter::{InputEdit, Node, Parser, Point, Query, QueryCursor, QueryError};
 
 use crate::{
   error::OhNo,
   kakoune::{
     buffer::BufferId,
+    history::{Modification, ModificationKind, UncommittedModifications},
     selection::{ObjectFlags, Pos, Sel, SelectMode},
     text_objects::OperationMode,
   },
+  protocol::request::BufferUpdate,
   server::{fifo::Fifo, resources::ServerResources},
 };
 
-use super::{highlighting::KakHighlightRange, languages::Language, nav};
+use super::{
+  diagnostics::{self, Diagnostic},
+  edits::{self, Edit},
+  folds::{self, Fold},
+  highlighting::{HighlightChunk, HighlightChunks, HighlightConfig, InjectionTrees},
+  indents,
+  languages::Language,
+  locals::{LocalSymbol, LocalsConfig},
+  nav,
+  playground::{self, QueryCapture},
+  replace, structural, swap, syntax_tree,
+  tags::Symbol,
+};
 
 /// Lang-keyed trees.
 #[derive(Default)]
@@ -31,16 +49,18 @@ impl Trees {
     resources: &mut ServerResources,
     lang: &Language,
     id: &BufferId,
+    update: BufferUpdate,
   ) -> Result<&mut TreeState, OhNo> {
     match self.trees.entry(id.clone()) {
       Entry::Occupied(entry) => {
         let tree = entry.into_mut();
+        tree.update = update;
         tree.change_lang(lang)?;
         Ok(tree)
       }
 
       Entry::Vacant(entry) => {
-        let tree = TreeState::new(resources, lang)?;
+        let tree = TreeState::new(resources, lang, update)?;
 
         self.by_token.insert(*tree.fifo.token(), id.clone());
         Ok(entry.insert(tree))
@@ -66,7 +86,16 @@ impl Trees {
     self
       .by_token
       .get(tkn)
-      .ok_or_else(|| OhNo::UnknownToken { tkn: *tkn })
+      .ok_or(OhNo::UnknownToken { tkn: *tkn })
+  }
+
+  pub fn trees_mut(&mut self) -> impl Iterator<Item = (&BufferId, &mut TreeState)> {
+    self.trees.iter_mut()
+  }
+
+  /// Whether any tree has highlights that have not been sent yet.
+  pub fn has_pending_highlights(&self) -> bool {
+    self.trees.values().any(TreeState::has_pending_highlights)
   }
 
   pub fn delete_tree(&mut self, id: &BufferId) {
@@ -81,27 +110,45 @@ impl Trees {
 ///
 /// A tree-sitter tree represents a parsed buffer in a given state. It can be walked with queries and updated.
 pub struct TreeState {
-  // this will be useful for #26
   parser: Parser,
   tree: tree_sitter::Tree,
   buf: String,
+  timestamp: u64,
+  update: BufferUpdate,
+
+  // history id of the buffer, along with the number of its uncommitted modifications already applied
+  history: Option<(u64, usize)>,
+
   lang: String,
   fifo: Fifo,
+  hl_chunks: HighlightChunks,
+  injection_trees: InjectionTrees,
 
-  // TODO: for now, we don’t support custom highligthing, and hence have to use tree-sitter-highlight; see
-  // #26 for further information
-  highlighter: tree_sitter_highlight::Highlighter,
+  // rainbow delimiters, chunked the same way as highlights
+  rainbow_chunks: HighlightChunks,
+
+  // local definitions, whose changes invalidate the highlights of their whole scope
+  locals_config: Option<Arc<LocalsConfig>>,
+
+  // lines edited since the last reparse, along with whether text was deleted from them
+  edited_lines: Vec<(Range<usize>, bool)>,
+
+  // diagnostics of the current buffer, as last sent to Kakoune
+  diagnostics: Vec<Diagnostic>,
 }
 
 impl TreeState {
-  pub fn new(resources: &mut ServerResources, lang: &Language) -> Result<Self, OhNo> {
+  pub fn new(
+    resources: &mut ServerResources,
+    lang: &Language,
+    update: BufferUpdate,
+  ) -> Result<Self, OhNo> {
     let mut parser = Parser::new();
     parser.set_language(lang.lang())?;
 
     let tree = parser
       .parse("".as_bytes(), None)
       .ok_or(OhNo::CannotParseBuffer)?;
-    let highlighter = tree_sitter_highlight::Highlighter::new();
 
     let fifo = resources.new_fifo()?;
 
@@ -109,9 +156,17 @@ impl TreeState {
       parser,
       tree,
       buf: String::default(),
+      timestamp: 0,
+      update,
+      history: None,
       lang: lang.name.clone(),
       fifo,
-      highlighter,
+      hl_chunks: HighlightChunks::default(),
+      injection_trees: InjectionTrees::default(),
+      rainbow_chunks: HighlightChunks::default(),
+      locals_config: lang.locals_config.clone(),
+      edited_lines: Vec::new(),
+      diagnostics: Vec::new(),
     })
   }
 
@@ -123,25 +178,63 @@ impl TreeState {
     &self.fifo
   }
 
+  /// Kakoune timestamp of the buffer the tree was last computed from.
+  pub fn timestamp(&self) -> u64 {
+    self.timestamp
+  }
+
   pub fn change_lang(&mut self, lang: &Language) -> Result<(), OhNo> {
     lang.lang_name().clone_into(&mut self.lang);
+    self.locals_config.clone_from(&lang.locals_config);
 
     self.parser = Parser::new();
     self.parser.set_language(lang.lang())?;
-
+    self.invalidate_all();
     self.recompute_tree()
   }
 
   /// Read the associated FIFO, update the buffer and recompute the tree.
   ///
-  /// Return `true` if the buffer was updated.
+  /// Return `true` if the buffer was updated. If only modifications were sent, and they do not apply to the buffer,
+  /// [`OhNo::InvalidBufferModifications`] is returned, and the whole buffer must be sent again.
   pub fn update_buf(&mut self) -> Result<bool, OhNo> {
-    if self.fifo.read_to_buf(&mut self.buf)? {
-      self.recompute_tree()?;
-      return Ok(true);
+    let mut buf = String::new();
+    let Some(header) = self.fifo.read_to_buf(&mut buf)? else {
+      return Ok(false);
+    };
+
+    if !header.has_content {
+      let modifications = header
+        .modifications
+        .ok_or(OhNo::InvalidBufferModifications)?;
+      let updated = self.apply_modifications(modifications)?;
+      self.timestamp = header.timestamp;
+      return Ok(updated);
+    }
+
+    self.timestamp = header.timestamp;
+    self.history = header
+      .modifications
+      .map(|modifications| (modifications.history_id, modifications.modifications.len()));
+
+    match self.update {
+      BufferUpdate::Full => {
+        self.buf = buf;
+        self.invalidate_all();
+        self.recompute_tree()?;
+        Ok(true)
+      }
+
+      BufferUpdate::Incremental => self.edit_tree(buf),
     }
+  }
 
-    Ok(false)
+  /// Invalidate everything computed from the previous tree; used when reparsing from scratch.
+  fn invalidate_all(&mut self) {
+    self.hl_chunks.invalidate_all();
+    self.rainbow_chunks.invalidate_all();
+    self.injection_trees.clear();
+    self.edited_lines.clear();
   }
 
   fn recompute_tree(&mut self) -> Result<(), OhNo> {
@@ -152,29 +245,432 @@ impl TreeState {
     Ok(())
   }
 
+  /// Replace the buffer by editing the current tree and reparsing incrementally.
+  ///
+  /// Return `false` if the content of the buffer has not changed.
+  fn edit_tree(&mut self, buf: String) -> Result<bool, OhNo> {
+    let Some(edit) = compute_edit(&self.buf, &buf) else {
+      log::trace!("buffer content has not changed");
+      return Ok(false);
+    };
+
+    log::trace!("incremental edit: {edit:?}");
+
+    self.edit(&edit);
+    self.buf = buf;
+    self.reparse()?;
+    Ok(true)
+  }
+
+  /// Apply the modifications of the buffer that have not been applied yet, and reparse incrementally.
+  ///
+  /// Modifications are only known since the last committed undo group; they cannot be applied if the buffer was not
+  /// read in the same undo group, or if they do not match the content of the buffer.
+  fn apply_modifications(&mut self, modifications: UncommittedModifications) -> Result<bool, OhNo> {
+    let applied = match self.history.take() {
+      Some((history_id, applied))
+        if history_id == modifications.history_id
+          && applied <= modifications.modifications.len() =>
+      {
+        applied
+      }
+
+      _ => return Err(OhNo::InvalidBufferModifications),
+    };
+
+    let mut is_valid = true;
+    for modification in &modifications.modifications[applied..] {
+      let Some(edit) = apply_modification(&mut self.buf, modification) else {
+        is_valid = false;
+        break;
+      };
+
+      log::trace!("incremental edit: {edit:?}");
+      self.edit(&edit);
+    }
+
+    is_valid &= self.buf.lines().count() == modifications.line_count;
+
+    // the tree must follow the buffer, even if the modifications were only partially applied
+    if applied < modifications.modifications.len() {
+      self.reparse()?;
+    }
+
+    if !is_valid {
+      return Err(OhNo::InvalidBufferModifications);
+    }
+
+    self.history = Some((modifications.history_id, modifications.modifications.len()));
+    Ok(applied < modifications.modifications.len())
+  }
+
+  /// Edit the current tree, along with everything computed from it, according to an edit of the buffer.
+  fn edit(&mut self, edit: &InputEdit) {
+    self.tree.edit(edit);
+    self.injection_trees.edit(edit);
+
+    // highlights of the edited lines must be sent again
+    let lines = edit.start_position.row..edit.new_end_position.row + 1;
+    for chunks in [&mut self.hl_chunks, &mut self.rainbow_chunks] {
+      chunks.apply_edit(edit);
+      chunks.invalidate_lines(lines.clone());
+    }
+
+    for (lines, _) in &mut self.edited_lines {
+      *lines = shift_lines(lines, edit);
+    }
+
+    let is_deletion = edit.old_end_byte > edit.start_byte;
+    self.edited_lines.push((lines, is_deletion));
+  }
+
+  /// Reparse the buffer incrementally, reusing the edited tree.
+  fn reparse(&mut self) -> Result<(), OhNo> {
+    let tree = self
+      .parser
+      .parse(self.buf.as_bytes(), Some(&self.tree))
+      .ok_or(OhNo::CannotParseBuffer)?;
+
+    // highlights of any node whose syntax has changed must be sent again
+    let mut changed_lines = std::mem::take(&mut self.edited_lines);
+    changed_lines.extend(
+      self
+        .tree
+        .changed_ranges(&tree)
+        .map(|range| (range.start_point.row..range.end_point.row + 1, false)),
+    );
+    for chunks in [&mut self.hl_chunks, &mut self.rainbow_chunks] {
+      for (lines, _) in &changed_lines {
+        chunks.invalidate_lines(lines.clone());
+      }
+    }
+
+    self.tree = tree;
+    self.invalidate_local_scopes(&changed_lines);
+    Ok(())
+  }
+
+  /// Invalidate the highlights of the scopes of the local definitions on changed lines.
+  ///
+  /// References get the face of their definition, so they might change anywhere in the scope of a changed definition.
+  /// A deleted definition is not around anymore, so the innermost scope around the deletion is invalidated instead.
+  fn invalidate_local_scopes(&mut self, changed_lines: &[(Range<usize>, bool)]) {
+    let Some(ref locals_config) = self.locals_config else {
+      return;
+    };
+
+    let Some(end) = changed_lines.iter().map(|(lines, _)| lines.end).max() else {
+      return;
+    };
+
+    let locals = locals_config.resolve(self.tree.root_node(), &self.buf, Point::new(end, 0));
+    let intersects = |range: &tree_sitter::Range, lines: &Range<usize>| {
+      range.start_point.row < lines.end && lines.start <= range.end_point.row
+    };
+    let scope_lines = |scope: &tree_sitter::Range| scope.start_point.row..scope.end_point.row + 1;
+
+    for (lines, is_deletion) in changed_lines {
+      for (def, scope) in locals.definitions.iter().zip(&locals.scopes) {
+        if intersects(&def.range, lines) {
+          self.hl_chunks.invalidate_lines(scope_lines(scope));
+        }
+      }
+
+      if *is_deletion {
+        let innermost = locals
+          .walked_scopes
+          .iter()
+          .filter(|scope| intersects(scope, lines))
+          .min_by_key(|scope| scope.end_byte - scope.start_byte);
+        if let Some(scope) = innermost {
+          self.hl_chunks.invalidate_lines(scope_lines(scope));
+        }
+      }
+    }
+  }
+
+  /// Whether some highlight or rainbow chunks have not been highlighted yet.
+  pub fn has_pending_highlights(&self) -> bool {
+    self.hl_chunks.has_pending() || self.rainbow_chunks.has_pending()
+  }
+
+  /// Highlight the invalidated chunks of the buffer and its rainbow delimiters, returning the highlight and rainbow
+  /// chunks that need to be sent to Kakoune.
+  ///
+  /// If `viewports` is not empty, only the chunks around those lines are highlighted; the other ones are pending, and
+  /// are highlighted by a later call.
   pub fn highlight<'a>(
-    &'a mut self,
+    &mut self,
     lang: &'a Language,
-    injection_callback: impl FnMut(&str) -> Option<&'a tree_sitter_highlight::HighlightConfiguration>
-      + 'a,
-  ) -> Result<Vec<KakHighlightRange>, OhNo> {
-    let events = self
-      .highlighter
-      .highlight(
-        &lang.hl_config,
-        self.buf.as_bytes(),
-        None,
-        injection_callback,
+    injection_callback: impl Fn(&str) -> Option<&'a HighlightConfig>,
+    viewports: &[Range<usize>],
+  ) -> (Vec<HighlightChunk>, Vec<HighlightChunk>) {
+    let line_count = self.buf.lines().count().max(1);
+    let chunks = self.hl_chunks.refresh(line_count, viewports, |lines| {
+      lang.hl_config.highlight(
+        &self.tree,
+        &self.buf,
+        &mut self.injection_trees,
+        &injection_callback,
+        lines,
       )
-      .map_err(|err| OhNo::HighlightError {
-        err: err.to_string(),
-      })?;
+    });
+
+    // languages without rainbow delimiters still get empty chunks, clearing the ones of a previous language
+    let rainbows =
+      self
+        .rainbow_chunks
+        .refresh(line_count, viewports, |lines| match lang.rainbow_config {
+          Some(ref config) => config.rainbows(&self.tree, &self.buf, lines),
+          None => vec![Vec::new(); lines.len()],
+        });
+
+    (chunks, rainbows)
+  }
+
+  /// Diagnostics of the buffer.
+  pub fn diagnostics(&self) -> &[Diagnostic] {
+    &self.diagnostics
+  }
+
+  /// Recompute the diagnostics of the buffer; i.e. its syntax errors and lints.
+  ///
+  /// Return `true` if they have changed.
+  pub fn update_diagnostics(&mut self, lang: &Language) -> bool {
+    let mut diagnostics = diagnostics::syntax_errors(&self.tree, &self.buf);
+    if let Some(ref lint_config) = lang.lint_config {
+      diagnostics.extend(lint_config.lints(&self.tree, &self.buf));
+    }
+    diagnostics.sort_by_key(|diag| (diag.range.start_byte, diag.range.end_byte));
+
+    if diagnostics == self.diagnostics {
+      return false;
+    }
+
+    self.diagnostics = diagnostics;
+    true
+  }
+
+  /// Find the local symbol defined or referenced at the given position.
+  pub fn local_symbol(&self, lang: &Language, pos: Pos) -> Result<Option<LocalSymbol>, OhNo> {
+    let config = lang.locals_config.as_ref().ok_or(OhNo::UnsupportedLocals)?;
+    Ok(config.symbol_at(&self.tree, &self.buf, pos.into()))
+  }
+
+  /// Select the definition and references of the local symbols under the cursors of the selections.
+  ///
+  /// Selections whose cursor is not on a symbol are kept as-is.
+  pub fn select_references(&self, lang: &Language, selections: &[Sel]) -> Result<Vec<Sel>, OhNo> {
+    let mut sels = Vec::new();
+
+    for sel in selections {
+      match self.local_symbol(lang, sel.cursor)? {
+        Some(symbol) => sels.extend(
+          symbol
+            .ranges()
+            .iter()
+            .map(|range| Sel::from_ts_range(range, &self.buf)),
+        ),
+        None => sels.push(sel.clone()),
+      }
+    }
+
+    // several selections might be on the same symbol
+    sels.sort_by_key(|sel| (sel.anchor.min(sel.cursor), sel.anchor.max(sel.cursor)));
+    sels.dedup();
+
+    Ok(sels)
+  }
+
+  /// Fold or unfold the nodes on the lines of the cursors of the selections.
+  ///
+  /// Return all the folds of the buffer, including the already folded ones.
+  pub fn fold(
+    &self,
+    lang: &Language,
+    selections: &[Sel],
+    folded: &[Fold],
+    action: folds::Action,
+  ) -> Result<Vec<Fold>, OhNo> {
+    let config = lang.fold_config.as_ref().ok_or(OhNo::UnsupportedFolds)?;
+    let folds = config.folds(&self.tree, &self.buf);
+    let cursors: Vec<_> = selections.iter().map(|sel| sel.cursor).collect();
+
+    Ok(folds::apply(&folds, folded, &cursors, action))
+  }
 
-    Ok(KakHighlightRange::from_iter(
-      &self.buf,
-      &lang.hl_names,
-      events.flatten(),
-    ))
+  /// Labels of the context nodes enclosing a position, from the outermost to the innermost.
+  pub fn breadcrumb(&self, lang: &Language, pos: Pos) -> Result<Vec<String>, OhNo> {
+    let config = lang
+      .context_config
+      .as_ref()
+      .ok_or(OhNo::UnsupportedContext)?;
+    Ok(config.breadcrumb(&self.tree, &self.buf, pos.into()))
+  }
+
+  /// Captures of an arbitrary query.
+  pub fn query_captures(
+    &self,
+    lang: &Language,
+    query: &str,
+  ) -> Result<Vec<QueryCapture>, QueryError> {
+    playground::captures(lang.lang(), &self.tree, &self.buf, query)
+  }
+
+  /// Selections of the ranges replaced by edits computed on the buffer, along with their texts.
+  pub fn kak_edits(&self, edits: Vec<Edit>) -> Vec<(Sel, String)> {
+    edits
+      .into_iter()
+      .map(|edit| (edit.sel(&self.buf), edit.text))
+      .collect()
+  }
+
+  /// Edits replacing every match of a query with a template.
+  pub fn replace(&self, query: &Query, template: &str) -> Vec<Edit> {
+    replace::replacements(query, &self.tree, &self.buf, template)
+  }
+
+  /// Apply a structural edit to the nodes under selections.
+  ///
+  /// Return the edits, and the selections of the inserted texts once the edits are applied. Selections whose node
+  /// cannot be edited, or whose edit would overlap the edit of another selection, are ignored.
+  pub fn structural_edit(
+    &self,
+    lang: &Language,
+    selections: &[Sel],
+    op: &structural::Op,
+  ) -> Result<(Vec<Edit>, Vec<Sel>), OhNo> {
+    let template = match op {
+      structural::Op::Wrap { template } => {
+        let template =
+          lang
+            .wrap_templates
+            .get(template)
+            .ok_or_else(|| OhNo::UnknownWrapTemplate {
+              template: template.clone(),
+            })?;
+        Some(template.as_str())
+      }
+      _ => None,
+    };
+
+    let groups = selections.iter().flat_map(|sel| {
+      let node = self.find_sel_node(sel)?;
+      let edit = match op {
+        structural::Op::Raise => structural::raise(node, &self.buf),
+        structural::Op::Splice => structural::splice(node, &self.buf),
+        structural::Op::Wrap { .. } => structural::wrap(node, &self.buf, template?),
+      }?;
+
+      Some(vec![(edit, true)])
+    });
+
+    Ok(edits::merge(&self.buf, groups))
+  }
+
+  /// Split or join the innermost nodes whose layout can be toggled around the cursors of selections.
+  ///
+  /// Return the edits, and the selections of the toggled nodes once the edits are applied.
+  pub fn split_join(
+    &self,
+    lang: &Language,
+    selections: &[Sel],
+    indent_width: usize,
+  ) -> Result<(Vec<Edit>, Vec<Sel>), OhNo> {
+    let config = lang
+      .splitjoin_config
+      .as_ref()
+      .ok_or(OhNo::UnsupportedSplitJoin)?;
+    let groups = selections.iter().flat_map(|sel| {
+      let edit = config.toggle(&self.tree, &self.buf, sel.cursor.into(), indent_width)?;
+      Some(vec![(edit, true)])
+    });
+
+    Ok(edits::merge(&self.buf, groups))
+  }
+
+  /// Swap the nodes under selections with their siblings.
+  ///
+  /// Return the edits, and the selections of the moved nodes once the edits are applied. Selections whose node cannot
+  /// be swapped, or that would be swapped with a node already swapped for another selection, are ignored.
+  pub fn swap(&self, selections: &[Sel], dir: swap::Dir) -> (Vec<Edit>, Vec<Sel>) {
+    let groups = selections.iter().flat_map(|sel| {
+      let node = self.find_sel_node(sel)?;
+      let ([first, second], moved) = swap::swap(node, &self.buf, dir)?;
+      Some(vec![(first, moved == 0), (second, moved == 1)])
+    });
+
+    edits::merge(&self.buf, groups)
+  }
+
+  /// Select the nodes captured by an arbitrary query inside selections.
+  pub fn select_query(
+    &self,
+    query: &Query,
+    capture: &str,
+    selections: &[Sel],
+  ) -> Result<Vec<Sel>, OhNo> {
+    playground::select(query, capture, &self.tree, &self.buf, selections)
+  }
+
+  /// S-expression of the tree, or of the node covering a selection, depending on the scope.
+  pub fn show_tree(&self, sel: &Sel, scope: syntax_tree::Scope) -> String {
+    syntax_tree::show(&self.tree, &self.buf, sel, scope)
+  }
+
+  /// Symbols defined in the buffer, sorted by position.
+  pub fn symbols(&self, lang: &Language) -> Result<Vec<Symbol>, OhNo> {
+    let config = lang.tags_config.as_ref().ok_or(OhNo::UnsupportedTags)?;
+    Ok(config.symbols(&self.tree, &self.buf))
+  }
+
+  /// Compute the indentation of the lines of the selections.
+  ///
+  /// Blank lines are only indented if they contain the cursor of a selection, so that new lines can be indented while
+  /// reindenting several lines does not fill empty lines with whitespace. Lines that are already correctly indented are
+  /// not returned.
+  pub fn indents(
+    &self,
+    lang: &Language,
+    selections: &[Sel],
+    indent_width: usize,
+  ) -> Result<Vec<(usize, String)>, OhNo> {
+    let config = lang
+      .indent_config
+      .as_ref()
+      .ok_or(OhNo::UnsupportedIndents)?;
+    let lines: Vec<_> = self.buf.split('\n').collect();
+    let lines = &lines;
+
+    let mut line_nbs: Vec<_> = selections
+      .iter()
+      .flat_map(|sel| {
+        let (start, end) = (sel.anchor.min(sel.cursor), sel.anchor.max(sel.cursor));
+        (start.line..=end.line).filter(move |&line| {
+          line == sel.cursor.line
+            || lines
+              .get(line.wrapping_sub(1))
+              .is_some_and(|text| !text.trim().is_empty())
+        })
+      })
+      .collect();
+    line_nbs.sort();
+    line_nbs.dedup();
+
+    let indents = line_nbs
+      .into_iter()
+      .filter_map(|line| {
+        let row = line.checked_sub(1)?;
+        let text = lines.get(row)?;
+        let current = &text[..text.len() - text.trim_start().len()];
+        let level = config.indent_level(&self.tree, &self.buf, row);
+        let indent = indents::indent_str(level, indent_width);
+
+        (current != indent).then_some((line, indent))
+      })
+      .collect();
+
+    Ok(indents)
   }
 
   /// Get the text-objects for the given pattern.
@@ -193,7 +689,7 @@ impl TreeState {
     let query = lang
       .textobject_query
       .as_ref()
-      .ok_or_else(|| OhNo::UnsupportedTextObjects)?;
+      .ok_or(OhNo::UnsupportedTextObjects)?;
 
     // get captures’ nodes for the given pattern; this is a function because the pattern might be dynamically recomputed
     // (e.g. object mode)
@@ -559,7 +1055,7 @@ impl TreeState {
   }
 
   /// Find the node for a selection.
-  fn find_sel_node(&self, sel: &Sel) -> Option<Node> {
+  fn find_sel_node(&self, sel: &Sel) -> Option<Node<'_>> {
     log::trace!("finding node for selection {sel:?}");
 
     let start = sel.anchor.min(sel.cursor);
@@ -603,3 +1099,283 @@ impl TreeState {
     })
   }
 }
+
+/// Move a range of lines according to an edit.
+///
+/// Lines in the edited text are clamped to the end of the edit.
+fn shift_lines(lines: &Range<usize>, edit: &InputEdit) -> Range<usize> {
+  let start = edit.start_position.row;
+  let old_end = edit.old_end_position.row;
+  let new_end = edit.new_end_position.row;
+  let shift = |line: usize| {
+    if line < start {
+      line
+    } else if line > old_end {
+      line - old_end + new_end
+    } else {
+      line.min(new_end)
+    }
+  };
+
+  shift(lines.start)..shift(lines.end - 1) + 1
+}
+
+/// Compute the edit transforming `old` into `new`.
+///
+/// The edit covers the smallest range of bytes that differs between both buffers, by skipping their common prefix and
+/// suffix. Return [`None`] if both buffers are the same.
+fn compute_edit(old: &str, new: &str) -> Option<InputEdit> {
+  let (old_bytes, new_bytes) = (old.as_bytes(), new.as_bytes());
+
+  let mut start = old_bytes
+    .iter()
+    .zip(new_bytes)
+    .take_while(|(a, b)| a == b)
+    .count();
+
+  if start == old.len() && start == new.len() {
+    return None;
+  }
+
+  // never cut a UTF-8 character in half
+  while !old.is_char_boundary(start) || !new.is_char_boundary(start) {
+    start -= 1;
+  }
+
+  // the common suffix cannot overlap with the common prefix
+  let suffix = old_bytes[start..]
+    .iter()
+    .rev()
+    .zip(new_bytes[start..].iter().rev())
+    .take_while(|(a, b)| a == b)
+    .count();
+  let mut old_end = old.len() - suffix;
+  let mut new_end = new.len() - suffix;
+
+  while !old.is_char_boundary(old_end) || !new.is_char_boundary(new_end) {
+    old_end += 1;
+    new_end += 1;
+  }
+
+  let start_position = advance_point(Point::new(0, 0), &old_bytes[..start]);
+  let old_end_position = advance_point(start_position, &old_bytes[start..old_end]);
+  let new_end_position = advance_point(start_position, &new_bytes[start..new_end]);
+
+  Some(InputEdit {
+    start_byte: start,
+    old_end_byte: old_end,
+    new_end_byte: new_end,
+    start_position,
+    old_end_position,
+    new_end_position,
+  })
+}
+
+/// Apply a Kakoune modification to a buffer, and return the corresponding edit.
+///
+/// Return [`None`] if the modification does not apply to the buffer; e.g. its position is out of the buffer, or the
+/// deleted text is not the one in the buffer.
+fn apply_modification(buf: &mut String, modification: &Modification) -> Option<InputEdit> {
+  let start_position = Point::from(modification.pos);
+  let line_start = match start_position.row {
+    0 => 0,
+    row => buf.match_indices('\n').nth(row - 1)?.0 + 1,
+  };
+  let start = line_start + start_position.column;
+  if !buf.is_char_boundary(start) || buf[line_start..start].contains('\n') {
+    return None;
+  }
+
+  let text = &modification.text;
+  let end = start + text.len();
+  let end_position = advance_point(start_position, text.as_bytes());
+
+  let edit = match modification.kind {
+    ModificationKind::Insert => {
+      buf.insert_str(start, text);
+      InputEdit {
+        start_byte: start,
+        old_end_byte: start,
+        new_end_byte: end,
+        start_position,
+        old_end_position: start_position,
+        new_end_position: end_position,
+      }
+    }
+
+    ModificationKind::Delete => {
+      if buf.get(start..end) != Some(text.as_str()) {
+        return None;
+      }
+
+      buf.replace_range(start..end, "");
+      InputEdit {
+        start_byte: start,
+        old_end_byte: end,
+        new_end_byte: start,
+        start_position,
+        old_end_position: end_position,
+        new_end_position: start_position,
+      }
+    }
+  };
+
+  Some(edit)
+}
+
+/// Advance a point by the given bytes.
+///
+/// Columns are expressed in bytes, as expected by tree-sitter.
+fn advance_point(mut point: Point, bytes: &[u8]) -> Point {
+  for &b in bytes {
+    if b == b'\n' {
+      point.row += 1;
+      point.column = 0;
+    } else {
+      point.column += 1;
+    }
+  }
+
+  point
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{InputEdit, Parser, Point};
+
+  use super::{apply_modification, compute_edit, shift_lines};
+  use crate::kakoune::history::Modification;
+
+  #[test]
+  fn edit_same_buffers() {
+    assert_eq!(compute_edit("fn foo() {}", "fn foo() {}"), None);
+    assert_eq!(compute_edit("", ""), None);
+  }
+
+  #[test]
+  fn edit_insertion() {
+    let old = "fn foo() {}\nfn bar() {}";
+    let new = "fn foo() {}\nfn bar(a: i32) {}";
+
+    assert_eq!(
+      compute_edit(old, new),
+      Some(InputEdit {
+        start_byte: 19,
+        old_end_byte: 19,
+        new_end_byte: 25,
+        start_position: Point::new(1, 7),
+        old_end_position: Point::new(1, 7),
+        new_end_position: Point::new(1, 13),
+      })
+    );
+  }
+
+  #[test]
+  fn edit_deletion() {
+    let old = "fn foo() {}\n\nfn bar() {}\n";
+    let new = "fn foo() {}\n";
+
+    assert_eq!(
+      compute_edit(old, new),
+      Some(InputEdit {
+        start_byte: 12,
+        old_end_byte: 25,
+        new_end_byte: 12,
+        start_position: Point::new(1, 0),
+        old_end_position: Point::new(3, 0),
+        new_end_position: Point::new(1, 0),
+      })
+    );
+  }
+
+  #[test]
+  fn edit_repeated_suffix() {
+    // the common prefix and suffix overlap; the edit must not go backwards
+    let edit = compute_edit("aaa", "aaaa").unwrap();
+    assert_eq!(edit.start_byte, 3);
+    assert_eq!(edit.old_end_byte, 3);
+    assert_eq!(edit.new_end_byte, 4);
+  }
+
+  #[test]
+  fn edit_unicode() {
+    // both symbols share their first byte
+    let edit = compute_edit("let x = 'ᾩ';", "let x = 'ᾨ';").unwrap();
+    assert_eq!(edit.start_byte, 9);
+    assert_eq!(edit.old_end_byte, 12);
+    assert_eq!(edit.new_end_byte, 12);
+    assert_eq!(edit.start_position, Point::new(0, 9));
+  }
+
+  #[test]
+  fn incremental_parsing() {
+    let old = "fn foo() {}\nfn bar() {}";
+    let new = "fn foo() {}\nstruct Zoo;\nfn bar(a: i32) { let x = 3; }";
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+
+    let mut tree = parser.parse(old, None).unwrap();
+    tree.edit(&compute_edit(old, new).unwrap());
+    let incremental = parser.parse(new, Some(&tree)).unwrap();
+    let full = parser.parse(new, None).unwrap();
+
+    assert_eq!(
+      incremental.root_node().to_sexp(),
+      full.root_node().to_sexp()
+    );
+  }
+
+  #[test]
+  fn modifications() {
+    let mut buf = "fn foo() {}\nfn bar() {}\n".to_owned();
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let mut tree = parser.parse(&buf, None).unwrap();
+
+    for modification in [
+      "+2.8|a: ué",
+      "-1.4|foo",
+      "+1.4|zoo\nstruct X;\n",
+      "+5.1|// end\n",
+    ] {
+      let modification = Modification::parse_kak_str(modification).unwrap();
+      tree.edit(&apply_modification(&mut buf, &modification).unwrap());
+    }
+    assert_eq!(buf, "fn zoo\nstruct X;\n() {}\nfn bar(a: ué) {}\n// end\n");
+
+    let incremental = parser.parse(&buf, Some(&tree)).unwrap();
+    let full = parser.parse(&buf, None).unwrap();
+    assert_eq!(
+      incremental.root_node().to_sexp(),
+      full.root_node().to_sexp()
+    );
+
+    // deleted text not matching, and positions out of the buffer or lines
+    for modification in ["-1.1|fx", "+7.1|x", "+1.20|x", "+4.13|x"] {
+      let modification = Modification::parse_kak_str(modification).unwrap();
+      assert_eq!(apply_modification(&mut buf, &modification), None);
+    }
+  }
+
+  #[test]
+  fn lines_shifting() {
+    let edit = |start, old_end, new_end| InputEdit {
+      start_byte: 0,
+      old_end_byte: 0,
+      new_end_byte: 0,
+      start_position: Point::new(start, 0),
+      old_end_position: Point::new(old_end, 0),
+      new_end_position: Point::new(new_end, 0),
+    };
+
+    // lines before the edit stay, lines after it move
+    assert_eq!(shift_lines(&(2..4), &edit(10, 12, 15)), 2..4);
+    assert_eq!(shift_lines(&(20..22), &edit(10, 12, 15)), 23..25);
+    assert_eq!(shift_lines(&(20..22), &edit(10, 15, 12)), 17..19);
+
+    // lines in deleted text are clamped to the end of the edit
+    assert_eq!(shift_lines(&(12..16), &edit(10, 15, 11)), 11..12);
+    assert_eq!(shift_lines(&(8..20), &edit(10, 15, 11)), 8..16);
+  }
+}
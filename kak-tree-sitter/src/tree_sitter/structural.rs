@@ -0,0 +1,201 @@
+//! Structural edits of nodes.
+//!
+//! - Raising a node replaces its parent with it. Delimited nodes — like the parenthesized arguments of a call — are
+//!   part of their parent when they are held in a field, so that raising an argument replaces the whole call.
+//! - Splicing removes the delimiters of the closest delimited node, keeping what is inside.
+//! - Wrapping surrounds a node with a template, in which `@node` is substituted with the node.
+//!
+//! A node is delimited if its first and last children are anonymous punctuation — e.g. `(` and `)`, or the quotes of a
+//! string.
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::Node;
+
+use crate::tree_sitter::{edits::Edit, replace};
+
+/// Structural edit to apply to the node under a selection.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+  /// Replace the parent of the node with the node.
+  Raise,
+
+  /// Remove the delimiters of the closest delimited node.
+  Splice,
+
+  /// Surround the node with a template of the language.
+  Wrap {
+    /// Name of the template.
+    template: String,
+  },
+}
+
+/// Replace the parent of a node with the node.
+pub fn raise(node: Node, source: &str) -> Option<Edit> {
+  let node = named(node)?;
+  let mut parent = node.parent()?;
+
+  while !parent.is_named()
+    || parent.byte_range() == node.byte_range()
+    || (is_delimited(parent) && is_in_field(parent))
+  {
+    parent = parent.parent()?;
+  }
+
+  Some(Edit {
+    range: parent.range(),
+    text: source.get(node.byte_range())?.to_owned(),
+  })
+}
+
+/// Remove the delimiters of a node, or of its closest delimited ancestor, and the whitespace around what they delimit.
+pub fn splice(node: Node, source: &str) -> Option<Edit> {
+  let mut node = node;
+  while !is_delimited(node) {
+    node = node.parent()?;
+  }
+
+  let open = node.child(0)?;
+  let close = node.child(node.child_count() - 1)?;
+
+  Some(Edit {
+    range: node.range(),
+    text: source
+      .get(open.end_byte()..close.start_byte())?
+      .trim()
+      .to_owned(),
+  })
+}
+
+/// Surround a node with a template.
+pub fn wrap(node: Node, source: &str, template: &str) -> Option<Edit> {
+  let node = named(node)?;
+  let text = source.get(node.byte_range())?;
+
+  Some(Edit {
+    range: node.range(),
+    text: replace::expand(template, |name| (name == "node").then_some(text)),
+  })
+}
+
+/// The node itself if it is named, or its closest named ancestor.
+fn named(node: Node) -> Option<Node> {
+  let mut node = node;
+  while !node.is_named() {
+    node = node.parent()?;
+  }
+
+  Some(node)
+}
+
+fn is_delimited(node: Node) -> bool {
+  let is_punctuation = |child: Option<Node>| {
+    child.is_some_and(|child| {
+      !child.is_named()
+        && !child.is_missing()
+        && child.kind().chars().all(|c| c.is_ascii_punctuation())
+    })
+  };
+  let count = node.child_count();
+
+  count >= 2 && is_punctuation(node.child(0)) && is_punctuation(node.child(count - 1))
+}
+
+/// Whether a node is held in a field of its parent.
+fn is_in_field(node: Node) -> bool {
+  let Some(parent) = node.parent() else {
+    return false;
+  };
+
+  let mut cursor = parent.walk();
+  if cursor.goto_first_child() {
+    loop {
+      if cursor.node() == node {
+        return cursor.field_name().is_some();
+      }
+
+      if !cursor.goto_next_sibling() {
+        break;
+      }
+    }
+  }
+
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Node, Parser, Point};
+
+  use super::{raise, splice, wrap};
+  use crate::tree_sitter::edits::Edit;
+
+  /// Apply an edit computed on the node at a position, and return the edited source.
+  fn edited(source: &str, pos: Point, f: impl Fn(Node, &str) -> Option<Edit>) -> Option<String> {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+    let node = tree.root_node().descendant_for_point_range(pos, pos)?;
+
+    let edit = f(node, source)?;
+    Some(format!(
+      "{}{}{}",
+      &source[..edit.range.start_byte],
+      edit.text,
+      &source[edit.range.end_byte..]
+    ))
+  }
+
+  #[test]
+  fn raise_nodes() {
+    let source = "fn f() { let x = foo(a, bar(b)); }";
+
+    // the arguments are part of the call
+    assert_eq!(
+      edited(source, Point::new(0, 28), raise).as_deref(),
+      Some("fn f() { let x = foo(a, b); }")
+    );
+    assert_eq!(
+      edited(source, Point::new(0, 21), raise).as_deref(),
+      Some("fn f() { let x = a; }")
+    );
+  }
+
+  #[test]
+  fn splice_nodes() {
+    let source = "fn f() { foo(( a + b )); }";
+
+    assert_eq!(
+      edited(source, Point::new(0, 15), splice).as_deref(),
+      Some("fn f() { foo(a + b); }")
+    );
+    assert_eq!(
+      edited(source, Point::new(0, 12), splice).as_deref(),
+      Some("fn f() { foo( a + b ); }")
+    );
+  }
+
+  #[test]
+  fn wrap_nodes() {
+    let source = "fn f() { g(x.y) }";
+
+    assert_eq!(
+      edited(source, Point::new(0, 11), |node, source| wrap(
+        node,
+        source,
+        "Some(@node)"
+      ))
+      .as_deref(),
+      Some("fn f() { g(Some(x).y) }")
+    );
+    assert_eq!(
+      edited(source, Point::new(0, 12), |node, source| wrap(
+        node,
+        source,
+        "{ @node }"
+      ))
+      .as_deref(),
+      Some("fn f() { g({ x.y }) }")
+    );
+  }
+}
@@ -0,0 +1,145 @@
+//! Swapping sibling nodes.
+//!
+//! A node is swapped with its previous or next named sibling. Only siblings in the same field of their parent — or
+//! both in none — are swapped, so that a function argument is swapped with another argument, but a callee is never
+//! swapped with its arguments; comments are skipped. If a node has no such sibling, its closest ancestor having one is
+//! swapped instead.
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::Node;
+
+use crate::tree_sitter::edits::Edit;
+
+/// Sibling to swap a node with.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dir {
+  Prev,
+  Next,
+}
+
+/// Swap a node — or its closest ancestor that can be swapped — with its sibling.
+///
+/// Return the two edits, sorted by position, and the index of the one moving the node.
+pub fn swap(node: Node, source: &str, dir: Dir) -> Option<([Edit; 2], usize)> {
+  let mut node = node;
+  let sibling = loop {
+    if node.is_named() {
+      if let Some(sibling) = sibling(node, dir) {
+        break sibling;
+      }
+    }
+
+    node = node.parent()?;
+  };
+
+  let (first, second) = match dir {
+    Dir::Prev => (sibling, node),
+    Dir::Next => (node, sibling),
+  };
+  let text = |node: Node| source.get(node.byte_range()).map(str::to_owned);
+
+  let edits = [
+    Edit {
+      range: first.range(),
+      text: text(second)?,
+    },
+    Edit {
+      range: second.range(),
+      text: text(first)?,
+    },
+  ];
+  let moved = match dir {
+    Dir::Prev => 0,
+    Dir::Next => 1,
+  };
+
+  Some((edits, moved))
+}
+
+/// Closest named sibling of a node in a direction, in the same field of their parent.
+fn sibling(node: Node, dir: Dir) -> Option<Node> {
+  let parent = node.parent()?;
+
+  // children of the parent, with their field names
+  let mut children = Vec::new();
+  let mut cursor = parent.walk();
+  if cursor.goto_first_child() {
+    loop {
+      children.push((cursor.node(), cursor.field_name()));
+
+      if !cursor.goto_next_sibling() {
+        break;
+      }
+    }
+  }
+
+  let index = children.iter().position(|(child, _)| *child == node)?;
+  let field = children[index].1;
+  let is_swappable = |(child, child_field): &&(Node, Option<&str>)| {
+    child.is_named() && !child.is_extra() && *child_field == field
+  };
+
+  let sibling = match dir {
+    Dir::Prev => children[..index].iter().rev().find(is_swappable),
+    Dir::Next => children[index + 1..].iter().find(is_swappable),
+  };
+
+  sibling.map(|(sibling, _)| *sibling)
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::{Parser, Point};
+
+  use super::{swap, Dir};
+
+  /// Swap the node at a position, and return the edited source.
+  fn swapped(source: &str, pos: Point, dir: Dir) -> Option<(String, usize)> {
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+    let node = tree.root_node().descendant_for_point_range(pos, pos)?;
+
+    let ([first, second], moved) = swap(node, source, dir)?;
+    let edited = format!(
+      "{}{}{}{}{}",
+      &source[..first.range.start_byte],
+      first.text,
+      &source[first.range.end_byte..second.range.start_byte],
+      second.text,
+      &source[second.range.end_byte..]
+    );
+
+    Some((edited, moved))
+  }
+
+  #[test]
+  fn swap_arguments() {
+    let source = "fn f() { foo(a, /* b */ bb, c) }";
+
+    assert_eq!(
+      swapped(source, Point::new(0, 13), Dir::Next),
+      Some(("fn f() { foo(bb, /* b */ a, c) }".to_owned(), 1))
+    );
+    assert_eq!(
+      swapped(source, Point::new(0, 24), Dir::Prev),
+      Some(("fn f() { foo(bb, /* b */ a, c) }".to_owned(), 0))
+    );
+    assert_eq!(
+      swapped(source, Point::new(0, 28), Dir::Next),
+      None // c is the last argument, and the call has no sibling either
+    );
+  }
+
+  #[test]
+  fn swap_ancestors() {
+    let source = "fn f() { foo(x, bar(y)) }";
+
+    // y has no sibling, and the callee bar is not swapped with its arguments
+    assert_eq!(
+      swapped(source, Point::new(0, 20), Dir::Prev),
+      Some(("fn f() { foo(bar(y), x) }".to_owned(), 0))
+    );
+  }
+}
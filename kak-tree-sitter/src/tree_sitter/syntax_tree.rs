@@ -0,0 +1,176 @@
+//! Syntax tree viewer.
+//!
+//! A tree is rendered as an S-expression, one named node per line, indented by depth. Each node shows its field name,
+//! if any, its kind and its range as a Kakoune selection; the node covering the cursor is marked with a comment.
+
+use serde::{Deserialize, Serialize};
+use tree_sitter::{Node, Point, Tree, TreeCursor};
+
+use crate::kakoune::selection::{Pos, Sel};
+
+/// Comment marking the node covering the cursor.
+const CURSOR_MARKER: &str = " ; <- cursor";
+
+/// Part of a tree to show.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+  /// The whole tree of the buffer.
+  Buffer,
+
+  /// The smallest node covering the selection.
+  Selection,
+}
+
+/// Render the part of a tree covered by a scope, marking the node under the cursor of the selection.
+pub fn show(tree: &Tree, source: &str, sel: &Sel, scope: Scope) -> String {
+  let start = Point::from(sel.anchor.min(sel.cursor));
+  let end = Point::from(sel.anchor.max(sel.cursor));
+  let root = match scope {
+    Scope::Buffer => tree.root_node(),
+    Scope::Selection => tree
+      .root_node()
+      .named_descendant_for_point_range(start, end)
+      .unwrap_or_else(|| tree.root_node()),
+  };
+
+  let cursor_point = Point::from(sel.cursor);
+  let cursor_node = root.named_descendant_for_point_range(cursor_point, cursor_point);
+
+  let renderer = Renderer {
+    lines: source.split('\n').collect(),
+    cursor_node: cursor_node.map(|node| node.id()),
+  };
+  let mut rendered = Vec::new();
+  renderer.render(&mut root.walk(), 0, &mut rendered);
+
+  rendered
+    .into_iter()
+    .map(|(line, is_cursor)| {
+      let marker = if is_cursor { CURSOR_MARKER } else { "" };
+      format!("{line}{marker}\n")
+    })
+    .collect()
+}
+
+struct Renderer<'a> {
+  lines: Vec<&'a str>,
+  cursor_node: Option<usize>,
+}
+
+impl Renderer<'_> {
+  /// Render the node under a tree cursor and its children, as lines marked with whether they show the cursor node.
+  fn render(&self, cursor: &mut TreeCursor, depth: usize, rendered: &mut Vec<(String, bool)>) {
+    let node = cursor.node();
+    let is_shown = node.is_named() || node.is_missing();
+
+    if is_shown {
+      let field = cursor
+        .field_name()
+        .map(|field| format!("{field}: "))
+        .unwrap_or_default();
+      let kind = if node.is_missing() {
+        format!("MISSING {}", node.kind())
+      } else {
+        node.kind().to_owned()
+      };
+
+      rendered.push((
+        format!(
+          "{indent}{field}({kind} {range}",
+          indent = "  ".repeat(depth),
+          range = self.range(&node).to_kak_str()
+        ),
+        Some(node.id()) == self.cursor_node,
+      ));
+    }
+
+    if cursor.goto_first_child() {
+      let child_depth = depth + usize::from(is_shown);
+
+      loop {
+        self.render(cursor, child_depth, rendered);
+
+        if !cursor.goto_next_sibling() {
+          break;
+        }
+      }
+
+      cursor.goto_parent();
+    }
+
+    if is_shown {
+      if let Some((line, _)) = rendered.last_mut() {
+        line.push(')');
+      }
+    }
+  }
+
+  /// Range of a node as an inclusive selection.
+  fn range(&self, node: &Node) -> Sel {
+    let start = node.start_position();
+    let end = node.end_position();
+
+    let cursor = if node.start_byte() == node.end_byte() {
+      Pos::from(start)
+    } else if end.column == 0 && end.row > 0 {
+      // the node ends with a newline
+      Pos {
+        line: end.row,
+        col: self.lines.get(end.row - 1).map_or(0, |line| line.len()) + 1,
+      }
+    } else {
+      Pos {
+        line: end.row + 1,
+        col: end.column,
+      }
+    };
+
+    Sel {
+      anchor: start.into(),
+      cursor,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::{show, Scope};
+  use crate::kakoune::selection::Sel;
+
+  #[test]
+  fn show_tree() {
+    let source = "fn f() {\n  a(1);\n}\n";
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+    let sel = Sel::parse_kak_str("2.5,2.5").unwrap();
+
+    assert_eq!(
+      show(&tree, source, &sel, Scope::Buffer),
+      "(source_file 1.1,3.2
+  (function_item 1.1,3.1
+    name: (identifier 1.4,1.4)
+    parameters: (parameters 1.5,1.6)
+    body: (block 1.8,3.1
+      (expression_statement 2.3,2.7
+        (call_expression 2.3,2.6
+          function: (identifier 2.3,2.3)
+          arguments: (arguments 2.4,2.6
+            (integer_literal 2.5,2.5))))))) ; <- cursor
+"
+    );
+
+    let sel = Sel::parse_kak_str("2.3,2.6").unwrap();
+    assert_eq!(
+      show(&tree, source, &sel, Scope::Selection),
+      "(call_expression 2.3,2.6
+  function: (identifier 2.3,2.3)
+  arguments: (arguments 2.4,2.6 ; <- cursor
+    (integer_literal 2.5,2.5)))
+"
+    );
+  }
+}
@@ -0,0 +1,164 @@
+//! Tags.
+//!
+//! The `tags.scm` query follows the conventions of `tree-sitter tags`: definitions are captured with
+//! `@definition.<kind>` — such as `@definition.function` or `@definition.class` — and their names with `@name`.
+//! References (`@reference.<kind>`) and documentation (`@doc`) captures are ignored.
+
+use std::cmp::Reverse;
+
+use tree_sitter::{Query, QueryCursor, Tree};
+
+use crate::{
+  error::OhNo, kakoune::selection::Pos, tree_sitter::highlighting::satisfies_general_predicates,
+};
+
+/// A symbol defined in a buffer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+  /// Kind of symbol; e.g. `function`.
+  pub kind: String,
+  pub name: String,
+
+  /// Position of the name of the symbol.
+  pub pos: Pos,
+
+  /// Number of symbols this symbol is defined in.
+  pub depth: usize,
+}
+
+/// Tags configuration of a language.
+pub struct TagsConfig {
+  query: Query,
+  name_capture: Option<u32>,
+
+  // kind of each capture, if it is a definition
+  kinds: Vec<Option<String>>,
+}
+
+impl TagsConfig {
+  /// Create a [`TagsConfig`] from a tags query.
+  pub fn new(lang: tree_sitter::Language, tags: &str) -> Result<Self, OhNo> {
+    let query = Query::new(lang, tags)?;
+    let name_capture = query.capture_index_for_name("name");
+    let kinds = query
+      .capture_names()
+      .iter()
+      .map(|name| name.strip_prefix("definition.").map(str::to_owned))
+      .collect();
+
+    Ok(Self {
+      query,
+      name_capture,
+      kinds,
+    })
+  }
+
+  /// Symbols defined in a tree, sorted by position.
+  pub fn symbols(&self, tree: &Tree, source: &str) -> Vec<Symbol> {
+    // (start, end, kind, name, position of the name)
+    let mut definitions = Vec::new();
+
+    let mut cursor = QueryCursor::new();
+    for qm in cursor.matches(&self.query, tree.root_node(), source.as_bytes()) {
+      if !satisfies_general_predicates(&self.query, &qm, source) {
+        continue;
+      }
+
+      let definition = qm.captures.iter().find_map(|capture| {
+        let kind = self.kinds[capture.index as usize].as_deref()?;
+        Some((capture.node, kind))
+      });
+      let name = qm
+        .captures
+        .iter()
+        .find(|capture| Some(capture.index) == self.name_capture)
+        .map(|capture| capture.node);
+
+      let (Some((node, kind)), Some(name)) = (definition, name) else {
+        continue;
+      };
+      let Ok(name_text) = name.utf8_text(source.as_bytes()) else {
+        continue;
+      };
+
+      definitions.push((
+        node.start_byte(),
+        node.end_byte(),
+        kind,
+        name_text,
+        Pos::from(name.start_position()),
+      ));
+    }
+
+    definitions.sort_by_key(|&(start, end, ..)| (start, Reverse(end)));
+    definitions.dedup_by_key(|&mut (start, end, ..)| (start, end));
+
+    // ends of the definitions enclosing the current one
+    let mut enclosing: Vec<usize> = Vec::new();
+    definitions
+      .into_iter()
+      .map(|(start, end, kind, name, pos)| {
+        while enclosing
+          .last()
+          .is_some_and(|&enclosing_end| enclosing_end <= start)
+        {
+          enclosing.pop();
+        }
+
+        let depth = enclosing.len();
+        enclosing.push(end);
+
+        Symbol {
+          kind: kind.to_owned(),
+          name: name.to_owned(),
+          pos,
+          depth,
+        }
+      })
+      .collect()
+  }
+}
+
+/// Render symbols as an outline; one line per symbol, as `<line>:<column>: <kind> <name>`, indented by their depth.
+pub fn outline(symbols: &[Symbol]) -> String {
+  symbols
+    .iter()
+    .map(|symbol| {
+      format!(
+        "{line}:{col}: {indent}{kind} {name}\n",
+        line = symbol.pos.line,
+        col = symbol.pos.col,
+        indent = "  ".repeat(symbol.depth),
+        kind = symbol.kind,
+        name = symbol.name,
+      )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use tree_sitter::Parser;
+
+  use super::{outline, TagsConfig};
+
+  #[test]
+  fn symbols_outline() {
+    let source = "struct S;\nimpl S {\n  fn new() -> Self { S }\n}\nfn main() {}";
+    let tags = r#"
+      (struct_item name: (type_identifier) @name) @definition.class
+      (impl_item type: (type_identifier) @name) @definition.implementation
+      (function_item name: (identifier) @name) @definition.function
+    "#;
+    let config = TagsConfig::new(tree_sitter_rust::language(), tags).unwrap();
+
+    let mut parser = Parser::new();
+    parser.set_language(tree_sitter_rust::language()).unwrap();
+    let tree = parser.parse(source, None).unwrap();
+
+    assert_eq!(
+      outline(&config.symbols(&tree, source)),
+      "1:8: class S\n2:6: implementation S\n3:6:   function new\n5:4: function main\n"
+    );
+  }
+}
@@ -0,0 +1,382 @@
+//! Workspace symbol index.
+//!
+//! A workspace is a directory whose files are parsed in a background thread, without being opened in Kakoune. The
+//! symbols defined in those files — found with the `tags.scm` queries of their languages — are indexed, and the index
+//! is refreshed as files change on disk, until the last session using the workspace ends. Files are matched to
+//! languages with the extensions of the languages.
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, SystemTime},
+};
+
+use tree_sitter::Parser;
+
+use crate::tree_sitter::tags::{Symbol, TagsConfig};
+
+/// Delay between two scans of a workspace.
+const RESCAN_DELAY: Duration = Duration::from_secs(2);
+
+/// How often the indexing thread checks whether it should stop while waiting for the next scan.
+const STOP_CHECK_DELAY: Duration = Duration::from_millis(100);
+
+/// Directories that are never indexed, on top of hidden ones.
+const IGNORED_DIRS: [&str; 2] = ["node_modules", "target"];
+
+/// Files larger than this (in bytes) are not indexed; they are typically generated or data files.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Maximum number of symbols returned by a search.
+pub const MAX_WORKSPACE_SYMBOLS: usize = 200;
+
+/// A language that can be indexed.
+#[derive(Clone)]
+pub struct IndexedLang {
+  pub ts_lang: tree_sitter::Language,
+  pub extensions: Vec<String>,
+  pub tags_config: Arc<TagsConfig>,
+}
+
+/// Symbols of an indexed file.
+#[derive(Debug)]
+struct IndexedFile {
+  modified: SystemTime,
+  symbols: Vec<Symbol>,
+}
+
+type Index = HashMap<PathBuf, IndexedFile>;
+
+/// A symbol found in a workspace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WorkspaceSymbol {
+  /// Path of the file the symbol is defined in.
+  pub path: PathBuf,
+  pub symbol: Symbol,
+}
+
+/// An indexed workspace.
+pub struct Workspace {
+  index: Arc<Mutex<Index>>,
+  stop: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+
+  /// Sessions using the workspace; it is dropped once they have all ended.
+  sessions: HashSet<String>,
+}
+
+impl Workspace {
+  /// Start indexing a workspace in a background thread.
+  pub fn index(root: PathBuf, langs: Vec<IndexedLang>) -> Self {
+    let index = Arc::new(Mutex::new(Index::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let handle = {
+      let index = index.clone();
+      let stop = stop.clone();
+
+      thread::spawn(move || {
+        log::info!("indexing workspace {}", root.display());
+
+        while !stop.load(Ordering::Relaxed) {
+          rescan(&root, &langs, &index, &stop);
+
+          let mut waited = Duration::ZERO;
+          while waited < RESCAN_DELAY && !stop.load(Ordering::Relaxed) {
+            thread::sleep(STOP_CHECK_DELAY);
+            waited += STOP_CHECK_DELAY;
+          }
+        }
+
+        log::info!("stopped indexing workspace {}", root.display());
+      })
+    };
+
+    Self {
+      index,
+      stop,
+      handle: Some(handle),
+      sessions: HashSet::new(),
+    }
+  }
+
+  /// Mark the workspace as used by a session.
+  pub fn attach(&mut self, session: impl Into<String>) {
+    self.sessions.insert(session.into());
+  }
+
+  /// Mark the workspace as not used by a session anymore.
+  pub fn detach(&mut self, session: &str) {
+    self.sessions.remove(session);
+  }
+
+  /// Whether any session still uses the workspace.
+  pub fn is_used(&self) -> bool {
+    !self.sessions.is_empty()
+  }
+
+  /// Search symbols whose name fuzzy-matches a query, best matches first.
+  pub fn search(&self, query: &str) -> Vec<WorkspaceSymbol> {
+    let index = self.index.lock().expect("workspace index");
+    search(&index, query)
+  }
+}
+
+impl Drop for Workspace {
+  // the indexing thread uses grammars that are unloaded with the languages, so it must be done before they are
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
+  }
+}
+
+/// Scan a workspace, and (re)index the files that have changed since the last scan.
+fn rescan(root: &Path, langs: &[IndexedLang], index: &Mutex<Index>, stop: &AtomicBool) {
+  let mut files = Vec::new();
+  collect_files(root, langs, &mut files);
+
+  let mut parser = Parser::new();
+  for (path, lang) in &files {
+    if stop.load(Ordering::Relaxed) {
+      return;
+    }
+
+    let Ok(metadata) = fs::metadata(path) else {
+      continue;
+    };
+
+    if metadata.len() > MAX_FILE_SIZE {
+      continue;
+    }
+
+    let Ok(modified) = metadata.modified() else {
+      continue;
+    };
+
+    let is_up_to_date = index
+      .lock()
+      .expect("workspace index")
+      .get(path)
+      .is_some_and(|file| file.modified == modified);
+    if is_up_to_date {
+      continue;
+    }
+
+    let Ok(source) = fs::read_to_string(path) else {
+      continue;
+    };
+
+    if parser.set_language(lang.ts_lang).is_err() {
+      continue;
+    }
+
+    let Some(tree) = parser.parse(&source, None) else {
+      continue;
+    };
+
+    let symbols = lang.tags_config.symbols(&tree, &source);
+    index
+      .lock()
+      .expect("workspace index")
+      .insert(path.clone(), IndexedFile { modified, symbols });
+  }
+
+  // forget about removed files
+  index
+    .lock()
+    .expect("workspace index")
+    .retain(|path, _| files.iter().any(|(file, _)| file == path));
+}
+
+/// Recursively collect the files of a directory that can be indexed.
+///
+/// Symbolic links to files are followed, but not symbolic links to directories, which could form cycles.
+fn collect_files<'a>(
+  dir: &Path,
+  langs: &'a [IndexedLang],
+  files: &mut Vec<(PathBuf, &'a IndexedLang)>,
+) {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+
+  for entry in entries.flatten() {
+    let path = entry.path();
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+
+    if name.starts_with('.') {
+      continue;
+    }
+
+    let Ok(file_type) = entry.file_type() else {
+      continue;
+    };
+
+    if file_type.is_dir() {
+      if !IGNORED_DIRS.contains(&name.as_ref()) {
+        collect_files(&path, langs, files);
+      }
+    } else if file_type.is_file() || (file_type.is_symlink() && path.is_file()) {
+      let extension = path.extension().map(|ext| ext.to_string_lossy());
+      let lang = extension.and_then(|ext| {
+        langs
+          .iter()
+          .find(|lang| lang.extensions.iter().any(|e| *e == ext))
+      });
+
+      if let Some(lang) = lang {
+        files.push((path, lang));
+      }
+    }
+  }
+}
+
+/// Search an index.
+fn search(index: &Index, query: &str) -> Vec<WorkspaceSymbol> {
+  let mut matches: Vec<_> = index
+    .iter()
+    .flat_map(|(path, file)| file.symbols.iter().map(move |symbol| (path, symbol)))
+    .filter_map(|(path, symbol)| Some((fuzzy_score(query, &symbol.name)?, path, symbol)))
+    .collect();
+
+  matches.sort_by(|(score_a, path_a, a), (score_b, path_b, b)| {
+    score_b
+      .cmp(score_a)
+      .then(a.name.len().cmp(&b.name.len()))
+      .then(path_a.cmp(path_b))
+      .then(a.pos.cmp(&b.pos))
+  });
+
+  matches
+    .into_iter()
+    .take(MAX_WORKSPACE_SYMBOLS)
+    .map(|(_, path, symbol)| WorkspaceSymbol {
+      path: path.clone(),
+      symbol: symbol.clone(),
+    })
+    .collect()
+}
+
+/// Score how well a name fuzzy-matches a query; the higher, the better.
+///
+/// The characters of the query must appear in the name in the same order, ignoring case. Consecutive characters and
+/// characters starting words score more. Return [`None`] if the name does not match.
+fn fuzzy_score(query: &str, name: &str) -> Option<usize> {
+  let mut score = 0;
+  let mut name_chars = name.char_indices();
+  let mut prev: Option<(usize, char)> = None;
+  let mut last_match: Option<usize> = None;
+
+  for q in query.chars().flat_map(char::to_lowercase) {
+    loop {
+      let (i, c) = name_chars.next()?;
+      let is_word_start = match prev {
+        None => true,
+        Some((_, p)) => p == '_' || p == '-' || (p.is_lowercase() && c.is_uppercase()),
+      };
+      let is_consecutive = last_match.is_some_and(|last| prev.map(|(j, _)| j) == Some(last));
+      prev = Some((i, c));
+
+      if c.to_lowercase().eq(std::iter::once(q)) {
+        score += 1 + 3 * usize::from(is_consecutive) + 2 * usize::from(is_word_start);
+        last_match = Some(i);
+        break;
+      }
+    }
+  }
+
+  Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+  };
+
+  use super::{fuzzy_score, rescan, search, Index, IndexedLang, Workspace, MAX_FILE_SIZE};
+  use crate::tree_sitter::tags::TagsConfig;
+
+  #[test]
+  fn fuzzy_scores() {
+    assert_eq!(fuzzy_score("xyz", "foo"), None);
+    assert!(fuzzy_score("", "foo").is_some());
+    assert!(fuzzy_score("fb", "foo_bar") > fuzzy_score("fb", "fabric"));
+    assert!(fuzzy_score("foo", "foobar") > fuzzy_score("foo", "f_o_o"));
+    assert!(fuzzy_score("hb", "handleBuffer") > fuzzy_score("hb", "hobby"));
+  }
+
+  #[test]
+  fn index_workspace() {
+    let root = std::env::temp_dir().join(format!("kts-workspace-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::create_dir_all(root.join(".git")).unwrap();
+    fs::write(
+      root.join("src/lib.rs"),
+      "fn parse_buffer() {}\nfn print() {}",
+    )
+    .unwrap();
+    fs::write(root.join(".git/ignored.rs"), "fn parse_ignored() {}").unwrap();
+    fs::write(root.join("notes.txt"), "fn parse_notes() {}").unwrap();
+    std::os::unix::fs::symlink(root.join("src/lib.rs"), root.join("link.rs")).unwrap();
+
+    let mut generated = "fn parse_generated() {}\n".to_owned();
+    generated.push_str(&" ".repeat(MAX_FILE_SIZE as usize));
+    fs::write(root.join("src/generated.rs"), generated).unwrap();
+
+    let tags = "(function_item name: (identifier) @name) @definition.function";
+    let langs = [IndexedLang {
+      ts_lang: tree_sitter_rust::language(),
+      extensions: vec!["rs".to_owned()],
+      tags_config: Arc::new(TagsConfig::new(tree_sitter_rust::language(), tags).unwrap()),
+    }];
+    let index = Mutex::new(Index::new());
+    rescan(&root, &langs, &index, &AtomicBool::new(false));
+
+    let names = |query| -> Vec<_> {
+      search(&index.lock().unwrap(), query)
+        .into_iter()
+        .map(|found| (found.path, found.symbol.name))
+        .collect()
+    };
+    assert_eq!(
+      names("pb"),
+      vec![
+        (root.join("link.rs"), "parse_buffer".to_owned()),
+        (root.join("src/lib.rs"), "parse_buffer".to_owned())
+      ]
+    );
+
+    fs::remove_file(root.join("src/lib.rs")).unwrap();
+    rescan(&root, &langs, &index, &AtomicBool::new(false));
+    assert_eq!(names("p"), vec![]);
+
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn workspace_sessions() {
+    let root = std::env::temp_dir().join(format!("kts-workspace-{}", uuid::Uuid::new_v4()));
+    let mut workspace = Workspace::index(root, Vec::new());
+    assert!(!workspace.is_used());
+
+    workspace.attach("a");
+    workspace.attach("b");
+    workspace.detach("a");
+    assert!(workspace.is_used());
+
+    workspace.detach("b");
+    assert!(!workspace.is_used());
+  }
+}